@@ -6,7 +6,7 @@ use std::fs::File;
 use std::path::Path;
 
 fn main() {
-    // OpenGL 3.3 bindings
+    // OpenGL 3.3 core profile bindings
     let dest = env::var("OUT_DIR").unwrap();
     let mut file_gl = File::create(&Path::new(&dest).join("gl_bindings.rs")).unwrap();
     let gl_extensions = [
@@ -32,7 +32,7 @@ fn main() {
     let gl_reg = Registry::new(
         Api::Gl,
         (3, 3), // Open GL の対象バージョン
-        Profile::Compatibility,
+        Profile::Core,
         Fallbacks::All,
         gl_extensions,
     );
@@ -0,0 +1,1119 @@
+use alto::efx::AuxEffectSlot;
+use alto::{ext, AltoResult, AsBufferData, Buffer, Context, DeviceObject, OutputDevice, SampleFrame, Source, SourceState, StreamingSource};
+use alto::{Mc51Chn, Mc71Chn, McQuad, Mono, Stereo};
+use std::ffi::CString;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::channels::ChannelLayout;
+use crate::clock::AudioClock;
+use crate::device;
+use crate::dsp::Chain;
+use crate::effects::{self, EffectConfig, REVERB_PRESETS};
+use crate::resampler::Resampler;
+use crate::sfx::{SoundBank, SoundEffects};
+
+// flush / 再開直後の音の立ち上がりをなめらかにする長さ (ミリ秒)
+const FADE_IN_MS: u32 = 5;
+// 音量を変えたときに目標の音量までかける時間 (ミリ秒)
+const GAIN_RAMP_MS: u32 = 30;
+// 画面の座標 1 が何メートルか (ドップラー効果の強さが変わる)
+const UNIT_METRES: f32 = 5.0;
+const SPEED_OF_SOUND: f32 = 343.3;
+// キューが空くのを待つ間隔
+const OVERFLOW_WAIT: Duration = Duration::from_millis(5);
+// drain で待つ最大の時間 (一時停止したまま止められたときなど)
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+// デバイスが外れたときに開き直す間隔
+const RECOVER_INTERVAL: Duration = Duration::from_secs(1);
+
+// 再生キューの操作 (OpenAL のストリーミングソースなど)
+pub trait AudioQueue {
+    // 再生するフォーマットが変わった (積んであるバッファは捨てる)
+    fn configure(&mut self, rate: u32, layout: ChannelLayout);
+    // pts はパケット先頭のサンプルを鳴らす時刻 (libvlc_clock)
+    // samples は VLC の並びのインターリーブされた float
+    fn queue(&mut self, pts: i64, samples: &[f32]);
+    // これ以上積むと目標の遅延を超える
+    fn is_full(&mut self) -> bool;
+    fn pause(&mut self);
+    // delay は一時停止していた時間、積んであるバッファの pts をその分ずらす
+    fn resume(&mut self, delay: i64);
+    // 再生を止めて積んであるバッファを捨てる
+    fn clear(&mut self);
+    // いま聞こえているサンプルの時刻
+    fn time(&self) -> Option<i64>;
+    // 積んだサンプルをすべて鳴らし終えた
+    fn is_drained(&mut self) -> bool;
+    // 一覧の次の出力デバイスに切り替える
+    fn next_device(&mut self) -> Result<(), String> {
+        Err("This audio output has no devices".to_string())
+    }
+    // 音源の位置と速度 (リスナーは原点で -Z を向いている)
+    fn set_position(&mut self, _position: [f32; 3], _velocity: [f32; 3]) {}
+    // false にすると位置に関係なく元のチャンネルのまま鳴らす
+    fn set_spatialize(&mut self, _enabled: bool) {}
+    fn set_doppler(&mut self, _enabled: bool) {}
+    fn set_hrtf(&mut self, _enabled: bool) {}
+    // effects::REVERB_PRESETS の何番目か
+    fn set_reverb(&mut self, _preset: Option<usize>) {}
+    fn set_sounds(&mut self, _bank: Arc<SoundBank>) {}
+    // bank の何番目を鳴らすか
+    fn play_sound(&mut self, _index: usize) -> Result<(), String> {
+        Err("Sound effects are not supported by this output".to_string())
+    }
+    fn set_sound_volume(&mut self, _volume: f32) {}
+}
+
+// libvlc の音声コールバックと再生キューの橋渡し
+pub struct AudioBridge<Q: AudioQueue> {
+    queue: Q,
+    rate: u32,
+    channels: u32,
+    // flush された時刻、これより古いサンプルは捨てる
+    flush_pts: Option<i64>,
+    pause_pts: Option<i64>,
+    fade_in: bool,
+    gain: Gain,
+    dsp: Chain,
+    overflow: Overflow,
+    dropped: u64,
+}
+
+impl<Q: AudioQueue> AudioBridge<Q> {
+    pub fn new(queue: Q, rate: u32, channels: u32) -> AudioBridge<Q> {
+        AudioBridge {
+            queue,
+            rate,
+            channels,
+            flush_pts: None,
+            pause_pts: None,
+            fade_in: true,
+            gain: Gain::new(),
+            dsp: Chain::default(),
+            overflow: Overflow::Block,
+            dropped: 0,
+        }
+    }
+
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    pub fn set_dsp(&mut self, mut dsp: Chain) {
+        dsp.configure(self.rate, self.channels as usize);
+        self.dsp = dsp;
+    }
+
+    pub fn queue(&self) -> &Q {
+        &self.queue
+    }
+
+    // volume は libvlc の音量 (1.0 が 100%)
+    pub fn set_volume(&mut self, volume: f32, mute: bool) {
+        self.gain.set_volume(volume, mute);
+    }
+
+    pub fn queue_mut(&mut self) -> &mut Q {
+        &mut self.queue
+    }
+
+    pub fn channels(&self) -> u32 {
+        self.channels
+    }
+
+    pub fn configure(&mut self, rate: u32, layout: ChannelLayout) {
+        self.rate = rate;
+        self.channels = layout.channels();
+        self.flush_pts = None;
+        self.fade_in = true;
+        self.dsp.configure(rate, self.channels as usize);
+        self.queue.configure(rate, layout);
+        // 新しいストリームは一時停止していない状態で始まる
+        if self.pause_pts.take().is_some() {
+            self.queue.resume(0);
+        }
+    }
+
+    pub fn play(&mut self, samples: &[f32], pts: i64) {
+        let channels = self.channels as usize;
+        let frames = samples.len() / channels;
+        let mut pts = pts;
+        let mut skip = 0;
+        if let Some(flush_pts) = self.flush_pts {
+            if pts < flush_pts {
+                // flush より前のサンプルは捨てる
+                skip = ((flush_pts - pts) * self.rate as i64 / 1_000_000).min(frames as i64) as usize;
+                pts += skip as i64 * 1_000_000 / self.rate as i64;
+            }
+            if skip < frames {
+                self.flush_pts = None;
+            }
+        }
+        if skip >= frames {
+            return;
+        }
+
+        let mut samples = samples[skip * channels..frames * channels].to_vec();
+        if self.fade_in {
+            // 途中から再生を始めるとプツッと鳴るので短くフェードインする
+            let fade_frames = (self.rate * FADE_IN_MS / 1000).max(1) as usize;
+            for (i, frame) in samples.chunks_mut(channels).take(fade_frames).enumerate() {
+                let gain = i as f32 / fade_frames as f32;
+                for sample in frame {
+                    *sample *= gain;
+                }
+            }
+            self.fade_in = false;
+        }
+        self.dsp.process(&mut samples);
+        // 音量を上げても天井を超えないようにリミッターは最後にかける
+        self.gain.apply(&mut samples, channels, self.rate);
+        self.dsp.limit(&mut samples);
+        // リミッターの先読みの分だけ遅れて出てくる
+        pts -= self.dsp.latency() as i64 * 1_000_000 / self.rate as i64;
+        self.queue.queue(pts, &samples);
+    }
+
+    pub fn pause(&mut self, pts: i64) {
+        self.queue.pause();
+        self.pause_pts = Some(pts);
+    }
+
+    pub fn resume(&mut self, pts: i64) {
+        let delay = self.pause_pts.take().map(|paused| pts - paused).unwrap_or(0);
+        self.queue.resume(delay);
+    }
+
+    pub fn flush(&mut self, pts: i64) {
+        self.queue.clear();
+        self.flush_pts = Some(pts);
+        // 一時停止中のシークでは、止まっている時間を flush の時点から数え直す
+        self.pause_pts = self.pause_pts.map(|_| pts);
+        self.fade_in = true;
+        self.dsp.reset();
+    }
+}
+
+// ソフトウェアの音量、変えたときはプツッと鳴らないように少しずつ近づける
+struct Gain {
+    current: f32,
+    target: f32,
+}
+
+impl Gain {
+    fn new() -> Gain {
+        Gain {
+            current: 1.0,
+            target: 1.0,
+        }
+    }
+
+    // VLC のソフトウェア音量と同じく 3 乗して聞こえ方に合わせる
+    fn set_volume(&mut self, volume: f32, mute: bool) {
+        self.target = if mute { 0.0 } else { volume.max(0.0).powi(3) };
+    }
+
+    fn apply(&mut self, samples: &mut [f32], channels: usize, rate: u32) {
+        if self.current == self.target {
+            if self.current != 1.0 {
+                for sample in samples.iter_mut() {
+                    *sample *= self.current;
+                }
+            }
+            return;
+        }
+
+        let ramp_frames = (rate * GAIN_RAMP_MS / 1000).max(1);
+        let step = 1.0 / ramp_frames as f32;
+        for frame in samples.chunks_mut(channels) {
+            if self.current < self.target {
+                self.current = (self.current + step).min(self.target);
+            } else if self.current > self.target {
+                self.current = (self.current - step).max(self.target);
+            }
+            for sample in frame {
+                *sample *= self.current;
+            }
+        }
+    }
+}
+
+// キューがいっぱいのときの動作
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    // 空くまで libvlc のスレッドを待たせる
+    Block,
+    // パケットを捨てる
+    Drop,
+}
+
+// これより短いとパケット 1 つ分もたまらずに途切れ続ける
+pub const MIN_LATENCY_MS: u32 = 20;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PoolConfig {
+    // OpenAL のバッファの数
+    pub buffers: usize,
+    // これ以上先まで積まない (ミリ秒)
+    pub target_latency_ms: u32,
+    pub overflow: Overflow,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            buffers: 16,
+            target_latency_ms: 200,
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+// OpenAL のストリーミングソースに積む
+// バッファは最大 config.buffers 個まで作って使い回す
+pub struct OpenAlQueue {
+    context: Context,
+    source: StreamingSource,
+    clock: AudioClock,
+    // デバイスの周波数、VLC から受け取る周波数と違えば変換する
+    rate: u32,
+    input_rate: u32,
+    resampler: Option<Resampler>,
+    // 選んでいるデバイスの名前 (None は既定のデバイス)
+    device: Option<CString>,
+    // デバイスが外れて開き直せていない
+    lost: bool,
+    retry_at: Instant,
+    // VLC から受け取る配置と OpenAL に渡す配置
+    layout: ChannelLayout,
+    output: ChannelLayout,
+    spatialize: bool,
+    doppler: bool,
+    effects: EffectConfig,
+    reverb_slot: Option<AuxEffectSlot>,
+    sounds: Option<SoundEffects>,
+    sound_volume: f32,
+    // AL_EXT_FLOAT32 があれば float のまま渡す
+    float: bool,
+    paused: bool,
+    config: PoolConfig,
+    free: Vec<Buffer>,
+    allocated: usize,
+    refill: Refill,
+}
+
+// 音が途切れたあと、ある程度たまるまで再生を始めない
+struct Refill {
+    starving: bool,
+    underruns: u32,
+}
+
+impl Refill {
+    fn new() -> Refill {
+        Refill {
+            starving: true,
+            underruns: 0,
+        }
+    }
+
+    // 鳴っているはずのソースが止まっていたら途切れた
+    fn check(&mut self, paused: bool, playing: bool) {
+        if paused || self.starving || playing {
+            return;
+        }
+        self.underruns += 1;
+        self.starving = true;
+        println!("audio underrun ({} times)", self.underruns);
+    }
+
+    // 目標の半分たまったら再生を始める
+    fn ready(&mut self, paused: bool, pending_ms: u32, target_ms: u32) -> bool {
+        if pending_ms < target_ms / 2 {
+            return false;
+        }
+        self.force(paused)
+    }
+
+    // たまるのを待たずに始める
+    fn force(&mut self, paused: bool) -> bool {
+        if paused || !self.starving {
+            return false;
+        }
+        self.starving = false;
+        true
+    }
+}
+
+impl OpenAlQueue {
+    pub fn new(
+        context: Context,
+        rate: u32,
+        layout: ChannelLayout,
+        config: PoolConfig,
+        effects: EffectConfig,
+    ) -> Result<OpenAlQueue, String> {
+        let source = context
+            .new_streaming_source()
+            .map_err(|err| err.to_string())?;
+        let float = context.is_extension_present(ext::Al::Float32);
+        let device = context.device().specifier().map(CString::from);
+        let mut queue = OpenAlQueue {
+            context,
+            source,
+            clock: AudioClock::new(rate),
+            rate,
+            input_rate: rate,
+            resampler: None,
+            device,
+            lost: false,
+            retry_at: Instant::now(),
+            layout,
+            output: layout,
+            spatialize: false,
+            doppler: false,
+            effects,
+            reverb_slot: None,
+            sounds: None,
+            sound_volume: 1.0,
+            float,
+            paused: false,
+            config,
+            free: Vec::with_capacity(config.buffers),
+            allocated: 0,
+            refill: Refill::new(),
+        };
+        queue.apply_spatial();
+        queue.apply_reverb();
+        queue.configure(rate, layout);
+        Ok(queue)
+    }
+
+    // 音源のエフェクトスロット 0 にリバーブをつなぐ
+    fn apply_reverb(&mut self) {
+        let index = match self.effects.reverb {
+            Some(index) => index,
+            None => {
+                if self.reverb_slot.take().is_some() {
+                    self.source.clear_aux_send(0).ok();
+                }
+                println!("reverb: off");
+                return;
+            }
+        };
+        let (name, preset) = &REVERB_PRESETS[index];
+        let result = effects::reverb_slot(&self.context, preset)
+            .and_then(|mut slot| self.source.set_aux_send(0, &mut slot).map(|_| slot).map_err(|err| err.to_string()));
+        match result {
+            Ok(slot) => {
+                self.reverb_slot = Some(slot);
+                println!("reverb: {} (applied)", name);
+            }
+            Err(err) => println!("reverb: {} (not applied: {})", name, err),
+        }
+    }
+
+    // 効果音のバッファとソースをいまのコンテキストに作る
+    fn create_sounds(&mut self, bank: Arc<SoundBank>) {
+        // 前のソースを先に消す
+        self.sounds = None;
+        match SoundEffects::new(&self.context, bank, self.sound_volume) {
+            Ok(sounds) => self.sounds = Some(sounds),
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    // デバイスの周波数が変わったときだけ変換をやり直す
+    fn update_rate(&mut self) {
+        let rate = device::device_frequency(self.context.device()).unwrap_or(self.rate);
+        if rate != self.rate {
+            self.rate = rate;
+            self.configure(self.input_rate, self.layout);
+        }
+    }
+
+    // 空間化しないときはリスナーに重ねて距離や向きの影響を受けないようにする
+    fn apply_spatial(&mut self) {
+        self.source.set_relative(!self.spatialize);
+        if !self.spatialize {
+            self.source.set_position([0.0, 0.0, 0.0]).unwrap();
+            self.source.set_velocity([0.0, 0.0, 0.0]).unwrap();
+        }
+        self.context
+            .set_doppler_factor(if self.doppler { 1.0 } else { 0.0 })
+            .unwrap();
+    }
+
+    // 出力先を切り替える、ALC_SOFT_reopen_device があれば積んであるバッファもそのまま鳴らす
+    pub fn switch_device(&mut self, name: Option<CString>) -> Result<(), String> {
+        match device::reopen_device(self.context.device(), name.as_deref()) {
+            Some(true) => {
+                // 開き直すと HRTF の設定が既定に戻るのでかけ直す
+                self.set_hrtf(self.effects.hrtf);
+                self.update_rate();
+            }
+            Some(false) => return Err(format!("Failed to reopen audio device {:?}", name)),
+            None => {
+                let output = self
+                    .context
+                    .device()
+                    .alto()
+                    .open(name.as_deref())
+                    .map_err(|err| format!("Failed to open audio device {:?}: {}", name, err))?;
+                let context = open_context(&output, self.effects.hrtf)?;
+                self.replace_context(context)?;
+            }
+        }
+        println!(
+            "audio device: {}",
+            name.as_ref().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| "(default)".to_string())
+        );
+        self.device = name;
+        self.lost = false;
+        Ok(())
+    }
+
+    // 別のデバイスのコンテキストに作り直す、積んであったバッファは捨てる
+    fn replace_context(&mut self, context: Context) -> Result<(), String> {
+        self.clear();
+        // エフェクトスロットは前のコンテキストのもの
+        if self.reverb_slot.take().is_some() {
+            self.source.clear_aux_send(0).ok();
+        }
+        self.free.clear();
+        self.allocated = 0;
+        let bank = self.sounds.take().map(|sounds| sounds.bank());
+        self.source = context.new_streaming_source().map_err(|err| err.to_string())?;
+        self.float = context.is_extension_present(ext::Al::Float32);
+        self.rate = device::device_frequency(context.device()).unwrap_or(self.rate);
+        self.context = context;
+        self.apply_spatial();
+        self.apply_reverb();
+        if let Some(bank) = bank {
+            self.create_sounds(bank);
+        }
+        self.configure(self.input_rate, self.layout);
+        if self.paused {
+            self.source.pause();
+        }
+        Ok(())
+    }
+
+    // デバイスが外れていたら既定のデバイスで開き直す
+    fn check_device(&mut self) {
+        if !self.lost {
+            // ALC_EXT_disconnect がなければ外れたことはわからない
+            if self.context.device().connected() != Ok(false) {
+                return;
+            }
+            println!("audio device disconnected");
+            self.lost = true;
+        }
+        let now = Instant::now();
+        if now < self.retry_at {
+            return;
+        }
+        self.retry_at = now + RECOVER_INTERVAL;
+        if let Err(err) = self.switch_device(None) {
+            println!("{}", err);
+        }
+    }
+
+    // 再生し終わったバッファを回収する
+    fn reclaim(&mut self) {
+        for _i in 0..self.source.buffers_processed() {
+            match self.source.unqueue_buffer() {
+                Ok(buf) => {
+                    self.clock.unqueue();
+                    self.free.push(buf);
+                }
+                Err(err) => {
+                    println!("audio unqueue failed: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    // まだ再生されていない長さ (ミリ秒)
+    fn pending_ms(&self) -> u32 {
+        let offset = self.source.sample_offset().max(0) as u32;
+        let pending = self.clock.queued_frames().saturating_sub(offset);
+        (pending as u64 * 1000 / self.rate as u64) as u32
+    }
+
+    fn start_if_ready(&mut self) {
+        if self.refill.ready(self.paused, self.pending_ms(), self.config.target_latency_ms) {
+            self.source.play();
+        }
+    }
+
+    // 出力の配置とサンプル形式に合わせてバッファに書き込む
+    fn write_buffer(&self, buf: Option<Buffer>, samples: &[f32]) -> AltoResult<Buffer> {
+        let rate = self.rate as i32;
+        if self.float {
+            return match self.output {
+                ChannelLayout::Mono => fill::<Mono<f32>, _>(&self.context, buf, samples, rate),
+                ChannelLayout::Stereo => fill::<Stereo<f32>, _>(&self.context, buf, samples, rate),
+                ChannelLayout::Quad => fill::<McQuad<f32>, _>(&self.context, buf, samples, rate),
+                ChannelLayout::Surround51 => fill::<Mc51Chn<f32>, _>(&self.context, buf, samples, rate),
+                ChannelLayout::Surround71 => fill::<Mc71Chn<f32>, _>(&self.context, buf, samples, rate),
+            };
+        }
+
+        let samples: Vec<i16> = samples
+            .iter()
+            .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+            .collect();
+        let samples = &samples[..];
+        match self.output {
+            ChannelLayout::Mono => fill::<Mono<i16>, _>(&self.context, buf, samples, rate),
+            ChannelLayout::Stereo => fill::<Stereo<i16>, _>(&self.context, buf, samples, rate),
+            ChannelLayout::Quad => fill::<McQuad<i16>, _>(&self.context, buf, samples, rate),
+            ChannelLayout::Surround51 => fill::<Mc51Chn<i16>, _>(&self.context, buf, samples, rate),
+            ChannelLayout::Surround71 => fill::<Mc71Chn<i16>, _>(&self.context, buf, samples, rate),
+        }
+    }
+}
+
+fn fill<F: SampleFrame, B: AsBufferData<F>>(context: &Context, buf: Option<Buffer>, data: B, rate: i32) -> AltoResult<Buffer> {
+    match buf {
+        Some(mut buf) => {
+            buf.set_data::<F, B>(data, rate)?;
+            Ok(buf)
+        }
+        None => context.new_buffer::<F, B>(data, rate),
+    }
+}
+
+impl AudioQueue for OpenAlQueue {
+    fn configure(&mut self, rate: u32, layout: ChannelLayout) {
+        // 形式の違うバッファを同じソースに積めないので一度空にする
+        self.clear();
+        self.clock = AudioClock::new(self.rate);
+        self.input_rate = rate;
+        self.layout = layout;
+        // 空間化するときはモノラル、マルチチャンネルに対応していなければステレオにダウンミックスする
+        self.output = if self.spatialize {
+            ChannelLayout::Mono
+        } else if layout.is_multichannel() && !self.context.is_extension_present(ext::Al::McFormats) {
+            ChannelLayout::Stereo
+        } else {
+            layout
+        };
+        // OpenAL 側でさらに変換されないようにデバイスの周波数にそろえる
+        self.resampler = if rate != self.rate {
+            Some(Resampler::new(rate, self.rate, self.output.channels()))
+        } else {
+            None
+        };
+        println!(
+            "audio output: {}Hz {:?} -> {}Hz {:?} ({})",
+            rate,
+            layout,
+            self.rate,
+            self.output,
+            if self.float { "float32" } else { "s16" }
+        );
+    }
+
+    fn queue(&mut self, pts: i64, samples: &[f32]) {
+        self.check_device();
+        if self.lost {
+            return;
+        }
+        self.reclaim();
+        self.refill.check(self.paused, self.source.state() == SourceState::Playing);
+
+        let mut samples = self.layout.reorder(samples);
+        if self.output != self.layout {
+            samples = match self.output {
+                ChannelLayout::Mono => self.layout.downmix_mono(&samples),
+                _ => self.layout.downmix_stereo(&samples),
+            };
+        }
+        if let Some(resampler) = self.resampler.as_mut() {
+            samples = resampler.process(&samples);
+            if samples.is_empty() {
+                return;
+            }
+        }
+
+        let buf = match self.free.pop() {
+            Some(buf) => Some(buf),
+            None if self.allocated < self.config.buffers => {
+                self.allocated += 1;
+                None
+            }
+            None => {
+                // is_full を確認してから呼ばれるので通常はここに来ない
+                println!("audio buffer pool exhausted");
+                return;
+            }
+        };
+        // libvlc のスレッドなので失敗してもパケットを捨てるだけにする
+        let buf = match self.write_buffer(buf, &samples) {
+            Ok(buf) => buf,
+            Err(err) => {
+                // 使い回すはずだったバッファも失われている
+                self.allocated -= 1;
+                println!("audio buffer write failed: {}", err);
+                return;
+            }
+        };
+        if let Err((err, buf)) = self.source.queue_buffer(buf) {
+            self.free.push(buf);
+            println!("audio buffer queue failed: {}", err);
+            return;
+        }
+        self.clock
+            .queue(pts, samples.len() as u32 / self.output.channels());
+        self.start_if_ready();
+    }
+
+    fn is_full(&mut self) -> bool {
+        // デバイスが戻るまで libvlc を待たせる
+        self.check_device();
+        if self.lost {
+            return true;
+        }
+        self.reclaim();
+        if self.free.is_empty() && self.allocated >= self.config.buffers {
+            // 小さいパケットでバッファを使い切ったときは、たまっていなくても再生を始める
+            if self.refill.force(self.paused) {
+                self.source.play();
+            }
+            return true;
+        }
+        self.pending_ms() >= self.config.target_latency_ms
+    }
+
+    fn pause(&mut self) {
+        self.paused = true;
+        self.source.pause();
+    }
+
+    fn resume(&mut self, delay: i64) {
+        self.paused = false;
+        self.clock.shift(delay);
+        if !self.refill.starving && self.source.buffers_queued() > 0 {
+            self.source.play();
+        } else {
+            self.start_if_ready();
+        }
+    }
+
+    // 一時停止中なら resume されるまで止めたまま
+    fn clear(&mut self) {
+        self.source.stop();
+        self.reclaim();
+        self.refill.starving = true;
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+        }
+    }
+
+    fn time(&self) -> Option<i64> {
+        self.clock.time(self.source.sample_offset())
+    }
+
+    fn is_drained(&mut self) -> bool {
+        if self.lost {
+            return true;
+        }
+        self.reclaim();
+        if self.source.buffers_queued() == 0 {
+            return true;
+        }
+        // 目標の遅延までたまっていなくても残りを鳴らす
+        if self.refill.force(self.paused) {
+            self.source.play();
+        }
+        false
+    }
+
+    fn set_position(&mut self, position: [f32; 3], velocity: [f32; 3]) {
+        if self.spatialize {
+            self.source.set_position(position).unwrap();
+            self.source.set_velocity(velocity).unwrap();
+        }
+    }
+
+    fn set_spatialize(&mut self, enabled: bool) {
+        if self.spatialize == enabled {
+            return;
+        }
+        self.spatialize = enabled;
+        self.apply_spatial();
+        // 出力のチャンネル数が変わるので積んであるバッファは捨てる
+        self.configure(self.input_rate, self.layout);
+    }
+
+    fn set_doppler(&mut self, enabled: bool) {
+        self.doppler = enabled;
+        self.apply_spatial();
+    }
+
+    fn set_hrtf(&mut self, enabled: bool) {
+        self.effects.hrtf = enabled;
+        let device = self.context.device();
+        if let Some(attrs) = effects::hrtf_attrs(device, enabled) {
+            // ALC_SOFT_HRTF では alcResetDeviceSOFT で属性を変えられる
+            if let Err(err) = device.soft_reset(attrs) {
+                println!("Failed to reset audio device: {}", err);
+            }
+        }
+        effects::report_hrtf(device, enabled);
+        self.update_rate();
+    }
+
+    fn set_reverb(&mut self, preset: Option<usize>) {
+        self.effects.reverb = preset;
+        self.apply_reverb();
+    }
+
+    fn next_device(&mut self) -> Result<(), String> {
+        let next = device::next_output(self.context.device().alto(), self.device.as_deref()).ok_or("No audio device")?;
+        self.switch_device(Some(next))
+    }
+
+    fn set_sounds(&mut self, bank: Arc<SoundBank>) {
+        self.create_sounds(bank);
+    }
+
+    fn play_sound(&mut self, index: usize) -> Result<(), String> {
+        self.sounds.as_mut().ok_or("No sound effects loaded")?.play(index)
+    }
+
+    fn set_sound_volume(&mut self, volume: f32) {
+        self.sound_volume = volume;
+        if let Some(sounds) = self.sounds.as_mut() {
+            sounds.set_volume(volume);
+        }
+    }
+}
+
+// コンテキストを作ってリスナーを設定する
+// hrtf はデバイスが ALC_SOFT_HRTF に対応していれば属性として渡す
+pub fn open_context(device: &OutputDevice, hrtf: bool) -> Result<Context, String> {
+    let context = device
+        .new_context(effects::hrtf_attrs(device, hrtf))
+        .map_err(|err| format!("Failed to create audio context: {}", err))?;
+    effects::report_hrtf(device, hrtf);
+
+    // Configure listener
+    // 原点から画面 (z = -1) を見ている
+    context.set_position([0.0, 0.0, 0.0]).unwrap();
+    context.set_velocity([0.0, 0.0, 0.0]).unwrap();
+    context.set_orientation(([0.0, 0.0, -1.0], [0.0, 1.0, 0.0])).unwrap();
+    context.set_speed_of_sound(SPEED_OF_SOUND / UNIT_METRES).unwrap();
+    Ok(context)
+}
+
+// 最後まで鳴らし終えるまで libvlc のスレッドを待たせる
+// 一時停止中は待たない
+pub fn drain<Q: AudioQueue>(audio: &Mutex<AudioBridge<Q>>) {
+    let start = Instant::now();
+    loop {
+        let mut bridge = audio.lock().unwrap();
+        if bridge.queue.is_drained() || bridge.pause_pts.is_some() {
+            return;
+        }
+        drop(bridge);
+        if start.elapsed() > DRAIN_TIMEOUT {
+            println!("audio drain timed out");
+            return;
+        }
+        thread::sleep(OVERFLOW_WAIT);
+    }
+}
+
+// キューが空くまでロックを外して待ってから play する
+// Overflow::Drop のときは待たずに捨てる
+pub fn play_bounded<Q: AudioQueue>(audio: &Mutex<AudioBridge<Q>>, samples: &[f32], pts: i64) {
+    loop {
+        let mut bridge = audio.lock().unwrap();
+        if !bridge.queue.is_full() {
+            bridge.play(samples, pts);
+            return;
+        }
+        if bridge.overflow == Overflow::Drop {
+            bridge.dropped += 1;
+            println!("audio queue full, dropped {} packets", bridge.dropped);
+            return;
+        }
+        drop(bridge);
+        thread::sleep(OVERFLOW_WAIT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 1000;
+
+    #[derive(Default)]
+    struct TestQueue {
+        packets: Vec<(i64, Vec<f32>)>,
+        paused: bool,
+        // OpenAlQueue のソースと同じく、一時停止していなければ積んだときに鳴り始める
+        playing: bool,
+        delay: i64,
+        cleared: usize,
+    }
+
+    impl AudioQueue for TestQueue {
+        fn configure(&mut self, _rate: u32, _layout: ChannelLayout) {
+            self.packets.clear();
+        }
+
+        fn queue(&mut self, pts: i64, samples: &[f32]) {
+            self.packets.push((pts, samples.to_vec()));
+            self.playing = !self.paused;
+        }
+
+        fn is_full(&mut self) -> bool {
+            self.packets.len() >= 2
+        }
+
+        fn pause(&mut self) {
+            self.paused = true;
+            self.playing = false;
+        }
+
+        fn resume(&mut self, delay: i64) {
+            self.paused = false;
+            self.playing = !self.packets.is_empty();
+            self.delay += delay;
+        }
+
+        fn clear(&mut self) {
+            self.packets.clear();
+            self.playing = false;
+            self.cleared += 1;
+        }
+
+        fn time(&self) -> Option<i64> {
+            self.packets.first().map(|packet| packet.0)
+        }
+
+        // 確認するたびに 1 パケットずつ鳴らし終えたことにする
+        fn is_drained(&mut self) -> bool {
+            if self.paused || self.packets.is_empty() {
+                return self.packets.is_empty();
+            }
+            self.packets.remove(0);
+            false
+        }
+    }
+
+    // 1000Hz ステレオで frames フレーム分、値がすべて value のパケット
+    fn packet(frames: usize, value: f32) -> Vec<f32> {
+        vec![value; frames * 2]
+    }
+
+    fn bridge() -> AudioBridge<TestQueue> {
+        let mut bridge = AudioBridge::new(TestQueue::default(), RATE, 2);
+        bridge.fade_in = false;
+        bridge
+    }
+
+    #[test]
+    fn passes_packets_through() {
+        let mut bridge = bridge();
+        bridge.play(&packet(100, 7.0), 0);
+        bridge.play(&packet(100, 7.0), 100_000);
+        assert_eq!(bridge.queue().packets.len(), 2);
+        assert_eq!(bridge.queue().packets[1], (100_000, packet(100, 7.0)));
+    }
+
+    #[test]
+    fn flush_clears_queue() {
+        let mut bridge = bridge();
+        bridge.play(&packet(100, 7.0), 0);
+        bridge.flush(50_000);
+        assert_eq!(bridge.queue().cleared, 1);
+        assert!(bridge.queue().packets.is_empty());
+    }
+
+    #[test]
+    fn drops_packets_older_than_flush() {
+        let mut bridge = bridge();
+        bridge.flush(500_000);
+        bridge.play(&packet(100, 7.0), 0);
+        bridge.play(&packet(100, 7.0), 300_000);
+        assert!(bridge.queue().packets.is_empty());
+    }
+
+    #[test]
+    fn trims_first_packet_after_flush() {
+        let mut bridge = bridge();
+        bridge.flush(500_000);
+        // 400ms から始まる 150 フレーム (150ms) のうち、500ms 以降の 50 フレームが残る
+        bridge.play(&packet(150, 7.0), 400_000);
+        let (pts, samples) = &bridge.queue().packets[0];
+        assert_eq!(*pts, 500_000);
+        assert_eq!(samples.len(), 50 * 2);
+
+        // 一度切り詰めたら以降のパケットはそのまま
+        bridge.play(&packet(100, 7.0), 550_000);
+        assert_eq!(bridge.queue().packets[1], (550_000, packet(100, 7.0)));
+    }
+
+    #[test]
+    fn packets_after_flush_are_kept() {
+        let mut bridge = bridge();
+        bridge.flush(500_000);
+        bridge.play(&packet(100, 7.0), 600_000);
+        let (pts, samples) = &bridge.queue().packets[0];
+        assert_eq!(*pts, 600_000);
+        assert_eq!(samples.len(), 100 * 2);
+    }
+
+    #[test]
+    fn fades_in_after_flush() {
+        let mut bridge = bridge();
+        bridge.flush(0);
+        bridge.play(&packet(100, 0.5), 0);
+        let samples = &bridge.queue().packets[0].1;
+        let fade_frames = (RATE * FADE_IN_MS / 1000) as usize;
+        assert_eq!(samples[0], 0.0);
+        assert!(samples[2] < 0.5);
+        assert_eq!(samples[fade_frames * 2], 0.5);
+
+        // 2 つ目のパケットはフェードしない
+        bridge.play(&packet(100, 0.5), 100_000);
+        assert_eq!(bridge.queue().packets[1].1[0], 0.5);
+    }
+
+    #[test]
+    fn resume_shifts_by_paused_time() {
+        let mut bridge = bridge();
+        bridge.play(&packet(100, 7.0), 0);
+        bridge.pause(50_000);
+        assert!(bridge.queue().paused);
+        bridge.resume(250_000);
+        assert!(!bridge.queue().paused);
+        assert_eq!(bridge.queue().delay, 200_000);
+        assert_eq!(bridge.queue().packets.len(), 1);
+    }
+
+    #[test]
+    fn seek_while_paused_stays_paused() {
+        let mut bridge = bridge();
+        bridge.play(&packet(100, 7.0), 0);
+        bridge.pause(50_000);
+        bridge.flush(1_000_000);
+        bridge.play(&packet(100, 7.0), 1_000_000);
+        // resume されるまでは鳴らさない
+        assert!(bridge.queue().paused);
+        assert!(!bridge.queue().playing);
+        bridge.resume(1_300_000);
+        assert!(bridge.queue().playing);
+        // flush してから resume までの時間だけずらす
+        assert_eq!(bridge.queue().delay, 300_000);
+    }
+
+    #[test]
+    fn new_stream_is_not_paused() {
+        let mut bridge = bridge();
+        bridge.pause(50_000);
+        bridge.configure(RATE, ChannelLayout::Stereo);
+        bridge.play(&packet(100, 7.0), 0);
+        assert!(bridge.queue().playing);
+    }
+
+    #[test]
+    fn drops_when_full() {
+        let audio = Mutex::new(bridge());
+        audio.lock().unwrap().set_overflow(Overflow::Drop);
+        for i in 0..4 {
+            play_bounded(&audio, &packet(100, 7.0), i * 100_000);
+        }
+        let bridge = audio.lock().unwrap();
+        assert_eq!(bridge.queue().packets.len(), 2);
+        assert_eq!(bridge.dropped, 2);
+    }
+
+    #[test]
+    fn gain_ramps_to_volume() {
+        let mut bridge = bridge();
+        bridge.set_volume(0.5, false);
+        bridge.play(&packet(100, 1.0), 0);
+        let samples = &bridge.queue().packets[0].1;
+        // 急に変えずに少しずつ下げる
+        assert!(samples[0] > 0.9);
+        assert!(samples.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert_eq!(samples[samples.len() - 1], 0.125);
+    }
+
+    #[test]
+    fn mute_ramps_to_silence() {
+        let mut bridge = bridge();
+        bridge.set_volume(1.0, true);
+        bridge.play(&packet(100, 1.0), 0);
+        let samples = &bridge.queue().packets[0].1;
+        assert!(samples[0] > 0.9);
+        assert_eq!(samples[samples.len() - 1], 0.0);
+    }
+
+    #[test]
+    fn drain_waits_until_played_out() {
+        let audio = Mutex::new(bridge());
+        play_bounded(&audio, &packet(100, 7.0), 0);
+        play_bounded(&audio, &packet(100, 7.0), 100_000);
+        drain(&audio);
+        assert!(audio.lock().unwrap().queue().packets.is_empty());
+    }
+
+    #[test]
+    fn refill_waits_for_half_latency() {
+        let mut refill = Refill::new();
+        assert!(!refill.ready(false, 99, 200));
+        assert!(refill.ready(false, 100, 200));
+        // 始めたあとは何度も play しない
+        assert!(!refill.ready(false, 150, 200));
+    }
+
+    #[test]
+    fn refill_waits_while_paused() {
+        let mut refill = Refill::new();
+        assert!(!refill.ready(true, 200, 200));
+        assert!(!refill.force(true));
+        assert!(refill.force(false));
+    }
+
+    #[test]
+    fn underrun_restarts_refill() {
+        let mut refill = Refill::new();
+        // 始める前に止まっているのは途切れではない
+        refill.check(false, false);
+        assert_eq!(refill.underruns, 0);
+        assert!(refill.ready(false, 100, 200));
+
+        refill.check(false, true);
+        assert_eq!(refill.underruns, 0);
+        // 一時停止で止まっているのも数えない
+        refill.check(true, false);
+        assert_eq!(refill.underruns, 0);
+
+        refill.check(false, false);
+        assert_eq!(refill.underruns, 1);
+        assert!(refill.starving);
+        refill.check(false, false);
+        assert_eq!(refill.underruns, 1);
+        assert!(!refill.ready(false, 50, 200));
+        assert!(refill.ready(false, 100, 200));
+    }
+
+    #[test]
+    fn drain_returns_while_paused() {
+        let audio = Mutex::new(bridge());
+        play_bounded(&audio, &packet(100, 7.0), 0);
+        audio.lock().unwrap().pause(50_000);
+        drain(&audio);
+        assert_eq!(audio.lock().unwrap().queue().packets.len(), 1);
+    }
+}
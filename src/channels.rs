@@ -0,0 +1,212 @@
+// チャンネル配置
+// サンプルの並びは libvlc (VLC の WG4 順) と OpenAL で異なるので並べ替えてから渡す
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+    Quad,
+    Surround51,
+    Surround71,
+}
+
+// -3dB
+const HALF_POWER: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+impl ChannelLayout {
+    // 元のチャンネル数に一番近い、OpenAL で扱える配置
+    pub fn from_channels(channels: u32) -> ChannelLayout {
+        match channels {
+            0 | 1 => ChannelLayout::Mono,
+            2 => ChannelLayout::Stereo,
+            3 | 4 => ChannelLayout::Quad,
+            5 | 6 => ChannelLayout::Surround51,
+            _ => ChannelLayout::Surround71,
+        }
+    }
+
+    pub fn channels(&self) -> u32 {
+        match self {
+            ChannelLayout::Mono => 1,
+            ChannelLayout::Stereo => 2,
+            ChannelLayout::Quad => 4,
+            ChannelLayout::Surround51 => 6,
+            ChannelLayout::Surround71 => 8,
+        }
+    }
+
+    // AL_EXT_MCFORMATS が必要
+    pub fn is_multichannel(&self) -> bool {
+        self.channels() > 2
+    }
+
+    // OpenAL の i 番目のチャンネルが VLC の何番目か
+    // VLC: L R (Ls Rs) Rl Rr C LFE
+    // OpenAL: FL FR C LFE BL BR (SL SR)
+    fn vlc_to_al(&self) -> &'static [usize] {
+        match self {
+            ChannelLayout::Mono => &[0],
+            ChannelLayout::Stereo => &[0, 1],
+            ChannelLayout::Quad => &[0, 1, 2, 3],
+            ChannelLayout::Surround51 => &[0, 1, 4, 5, 2, 3],
+            ChannelLayout::Surround71 => &[0, 1, 6, 7, 4, 5, 2, 3],
+        }
+    }
+
+    // VLC の並びから OpenAL の並びにする
+    pub fn reorder(&self, samples: &[f32]) -> Vec<f32> {
+        let map = self.vlc_to_al();
+        if map.iter().enumerate().all(|(i, &j)| i == j) {
+            return samples.to_vec();
+        }
+        let mut output = Vec::with_capacity(samples.len());
+        for frame in samples.chunks_exact(map.len()) {
+            output.extend(map.iter().map(|&i| frame[i]));
+        }
+        output
+    }
+
+    // OpenAL の並びのサンプルをステレオにする
+    // デバイスがマルチチャンネルに対応していないとき用、LFE は捨てる
+    pub fn downmix_stereo(&self, samples: &[f32]) -> Vec<f32> {
+        let channels = self.channels() as usize;
+        let mut output = Vec::with_capacity(samples.len() / channels * 2);
+        for frame in samples.chunks_exact(channels) {
+            let (left, right) = match self {
+                ChannelLayout::Mono => (frame[0], frame[0]),
+                ChannelLayout::Stereo => (frame[0], frame[1]),
+                ChannelLayout::Quad => {
+                    let scale = 1.0 / (1.0 + HALF_POWER);
+                    (
+                        (frame[0] + HALF_POWER * frame[2]) * scale,
+                        (frame[1] + HALF_POWER * frame[3]) * scale,
+                    )
+                }
+                ChannelLayout::Surround51 => {
+                    let scale = 1.0 / (1.0 + 2.0 * HALF_POWER);
+                    let center = HALF_POWER * frame[2];
+                    (
+                        (frame[0] + center + HALF_POWER * frame[4]) * scale,
+                        (frame[1] + center + HALF_POWER * frame[5]) * scale,
+                    )
+                }
+                ChannelLayout::Surround71 => {
+                    let scale = 1.0 / (1.0 + 3.0 * HALF_POWER);
+                    let center = HALF_POWER * frame[2];
+                    (
+                        (frame[0] + center + HALF_POWER * (frame[4] + frame[6])) * scale,
+                        (frame[1] + center + HALF_POWER * (frame[5] + frame[7])) * scale,
+                    )
+                }
+            };
+            output.push(left);
+            output.push(right);
+        }
+        output
+    }
+
+    // OpenAL の並びのサンプルをモノラルにする
+    // OpenAL はモノラルの音源しか 3D 配置しないので空間化するとき用
+    pub fn downmix_mono(&self, samples: &[f32]) -> Vec<f32> {
+        if *self == ChannelLayout::Mono {
+            return samples.to_vec();
+        }
+        self.downmix_stereo(samples)
+            .chunks_exact(2)
+            .map(|frame| (frame[0] + frame[1]) * 0.5)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1 フレームの各チャンネルに番号を入れる
+    fn numbered(layout: ChannelLayout) -> Vec<f32> {
+        (0..layout.channels()).map(|i| i as f32).collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn picks_nearest_layout() {
+        assert_eq!(ChannelLayout::from_channels(1), ChannelLayout::Mono);
+        assert_eq!(ChannelLayout::from_channels(2), ChannelLayout::Stereo);
+        assert_eq!(ChannelLayout::from_channels(3), ChannelLayout::Quad);
+        assert_eq!(ChannelLayout::from_channels(6), ChannelLayout::Surround51);
+        assert_eq!(ChannelLayout::from_channels(8), ChannelLayout::Surround71);
+        assert_eq!(ChannelLayout::from_channels(9), ChannelLayout::Surround71);
+    }
+
+    #[test]
+    fn reorders_51_to_openal() {
+        // VLC: L R Rl Rr C LFE -> OpenAL: FL FR C LFE BL BR
+        let output = ChannelLayout::Surround51.reorder(&numbered(ChannelLayout::Surround51));
+        assert_eq!(output, vec![0.0, 1.0, 4.0, 5.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn reorders_71_to_openal() {
+        // VLC: L R Ls Rs Rl Rr C LFE -> OpenAL: FL FR C LFE BL BR SL SR
+        let mut input = numbered(ChannelLayout::Surround71);
+        input.extend(input.clone());
+        let output = ChannelLayout::Surround71.reorder(&input);
+        let frame = [0.0, 1.0, 6.0, 7.0, 4.0, 5.0, 2.0, 3.0];
+        assert_eq!(output, [frame, frame].concat());
+    }
+
+    #[test]
+    fn stereo_is_not_reordered() {
+        let input = vec![0.25, -0.5, 0.75, -1.0];
+        assert_eq!(ChannelLayout::Stereo.reorder(&input), input);
+    }
+
+    #[test]
+    fn downmixes_51_with_half_power() {
+        let layout = ChannelLayout::Surround51;
+        let scale = 1.0 / (1.0 + 2.0 * HALF_POWER);
+        // OpenAL の並び FL FR C LFE BL BR
+        assert_close(&layout.downmix_stereo(&[1.0, 0.0, 0.0, 0.0, 0.0, 0.0]), &[scale, 0.0]);
+        // センターとサラウンドは -3dB
+        assert_close(&layout.downmix_stereo(&[0.0, 0.0, 1.0, 0.0, 0.0, 0.0]), &[HALF_POWER * scale; 2]);
+        assert_close(&layout.downmix_stereo(&[0.0, 0.0, 0.0, 0.0, 0.0, 1.0]), &[0.0, HALF_POWER * scale]);
+        // LFE は捨てる
+        assert_close(&layout.downmix_stereo(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0]), &[0.0, 0.0]);
+    }
+
+    #[test]
+    fn downmixes_71_with_half_power() {
+        let layout = ChannelLayout::Surround71;
+        let scale = 1.0 / (1.0 + 3.0 * HALF_POWER);
+        let mut side_left = vec![0.0; 8];
+        side_left[6] = 1.0;
+        assert_close(&layout.downmix_stereo(&side_left), &[HALF_POWER * scale, 0.0]);
+        let mut back_right = vec![0.0; 8];
+        back_right[5] = 1.0;
+        assert_close(&layout.downmix_stereo(&back_right), &[0.0, HALF_POWER * scale]);
+    }
+
+    #[test]
+    fn downmixes_to_mono() {
+        assert_close(&ChannelLayout::Stereo.downmix_mono(&[1.0, 0.5]), &[0.75]);
+        assert_close(&ChannelLayout::Mono.downmix_mono(&[0.5, -0.5]), &[0.5, -0.5]);
+    }
+
+    #[test]
+    fn full_scale_never_clips() {
+        for layout in [ChannelLayout::Quad, ChannelLayout::Surround51, ChannelLayout::Surround71] {
+            for value in [1.0, -1.0] {
+                let input = vec![value; layout.channels() as usize * 4];
+                let stereo = layout.downmix_stereo(&input);
+                assert!(stereo.iter().all(|sample| sample.abs() <= 1.0 + 1e-6), "{:?}", layout);
+                let mono = layout.downmix_mono(&input);
+                assert!(mono.iter().all(|sample| sample.abs() <= 1.0 + 1e-6), "{:?}", layout);
+            }
+        }
+    }
+}
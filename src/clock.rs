@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+
+// 映像フレームの pts がこれ以上先なら、時計が飛んだとみなしてすぐに表示する
+const MAX_FRAME_WAIT: i64 = 1_000_000;
+
+// OpenAL に積んだバッファの pts と長さから、いま聞こえているサンプルの時刻を求める
+// 時刻は libvlc_clock() と同じマイクロ秒
+pub struct AudioClock {
+    rate: u32,
+    buffers: VecDeque<(i64, u32)>,
+}
+
+impl AudioClock {
+    pub fn new(rate: u32) -> AudioClock {
+        AudioClock {
+            rate,
+            buffers: VecDeque::new(),
+        }
+    }
+
+    // source.queue_buffer と同じ順番で呼ぶ
+    pub fn queue(&mut self, pts: i64, frames: u32) {
+        self.buffers.push_back((pts, frames));
+    }
+
+    // source.unqueue_buffer と同じ回数だけ呼ぶ
+    pub fn unqueue(&mut self) {
+        self.buffers.pop_front();
+    }
+
+    // offset フレーム鳴らし終えたバッファを取り除き、先頭のバッファの中での位置を返す
+    pub fn consume(&mut self, offset: u32) -> u32 {
+        let mut offset = offset;
+        while let Some(&(_, frames)) = self.buffers.front() {
+            if offset < frames {
+                break;
+            }
+            offset -= frames;
+            self.buffers.pop_front();
+        }
+        offset
+    }
+
+    // 一時停止していた間 VLC の時計は進まないので、積んであるバッファの pts をずらす
+    pub fn shift(&mut self, delay: i64) {
+        for buffer in self.buffers.iter_mut() {
+            buffer.0 += delay;
+        }
+    }
+
+    // キューに積んであるフレーム数
+    pub fn queued_frames(&self) -> u32 {
+        self.buffers.iter().map(|&(_, frames)| frames).sum()
+    }
+
+    // sample_offset は AL_SAMPLE_OFFSET (処理済みでキューに残っているバッファも含む)
+    pub fn time(&self, sample_offset: i32) -> Option<i64> {
+        let mut offset = sample_offset.max(0) as u32;
+        for &(pts, frames) in &self.buffers {
+            if offset < frames {
+                return Some(pts + self.frames_to_us(offset));
+            }
+            offset -= frames;
+        }
+        // 全部再生し終わった
+        self.buffers
+            .back()
+            .map(|&(pts, frames)| pts + self.frames_to_us(frames))
+    }
+
+    fn frames_to_us(&self, frames: u32) -> i64 {
+        frames as i64 * 1_000_000 / self.rate as i64
+    }
+}
+
+// 音声の時計が pts に追いついていればフレームを表示する
+// 音声がないときは届いたらすぐに表示する
+pub fn frame_due(audio_time: Option<i64>, pts: i64) -> bool {
+    match audio_time {
+        Some(time) => time >= pts || pts - time > MAX_FRAME_WAIT,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1000Hz なので 1 フレーム 1ms
+    fn two_buffers() -> AudioClock {
+        let mut clock = AudioClock::new(1000);
+        clock.queue(1_000_000, 100);
+        clock.queue(1_100_000, 100);
+        clock
+    }
+
+    #[test]
+    fn time_within_queued_buffers() {
+        let clock = two_buffers();
+        assert_eq!(clock.time(0), Some(1_000_000));
+        assert_eq!(clock.time(50), Some(1_050_000));
+        assert_eq!(clock.time(150), Some(1_150_000));
+        // 全部鳴らし終えたら最後のバッファの終わり
+        assert_eq!(clock.time(500), Some(1_200_000));
+        assert_eq!(AudioClock::new(1000).time(0), None);
+    }
+
+    #[test]
+    fn time_after_partial_consumption() {
+        let mut clock = two_buffers();
+        // 先頭のバッファを鳴らし終えて、次のバッファの 50 フレーム目
+        assert_eq!(clock.consume(150), 50);
+        assert_eq!(clock.queued_frames(), 100);
+        assert_eq!(clock.time(50), Some(1_150_000));
+        // 途中までなら取り除かない
+        assert_eq!(clock.consume(30), 30);
+        assert_eq!(clock.queued_frames(), 100);
+
+        let mut unqueued = two_buffers();
+        unqueued.unqueue();
+        assert_eq!(unqueued.time(20), Some(1_120_000));
+    }
+
+    #[test]
+    fn time_after_shift() {
+        let mut clock = two_buffers();
+        clock.shift(500_000);
+        assert_eq!(clock.time(0), Some(1_500_000));
+        assert_eq!(clock.time(150), Some(1_650_000));
+        // ずらしたあとに積んだバッファはそのまま
+        clock.queue(1_700_000, 100);
+        assert_eq!(clock.time(250), Some(1_750_000));
+    }
+
+    #[test]
+    fn frame_due_at_wait_limit() {
+        assert!(frame_due(Some(1_000), 1_000));
+        assert!(!frame_due(Some(1_000), 1_001));
+        // ちょうど MAX_FRAME_WAIT 先までは待つ
+        assert!(!frame_due(Some(0), MAX_FRAME_WAIT));
+        assert!(frame_due(Some(0), MAX_FRAME_WAIT + 1));
+        assert!(frame_due(None, i64::MAX));
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+// 音量の上限 (%)
+pub const MAX_VOLUME: i32 = 200;
+pub const MAX_SOUND_VOLUME: i32 = 100;
+
+// 次に起動したときも使う設定
+// "key=value" の行で保存する
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    // libvlc の音量 (100 が等倍)
+    pub volume: i32,
+    pub muted: bool,
+    // イコライザーのプリセットの名前 (None ならイコライザーなし)
+    pub equalizer: Option<String>,
+    // プリセットから変えたプリアンプ (dB)
+    pub preamp: Option<f32>,
+    // ファイルごとの音声の遅れ (ms)、0 のものは持たない
+    pub audio_delays: BTreeMap<String, i64>,
+    // 効果音の音量 (100 が等倍)
+    pub sound_volume: i32,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            volume: 100,
+            muted: false,
+            equalizer: None,
+            preamp: None,
+            audio_delays: BTreeMap::new(),
+            sound_volume: 100,
+        }
+    }
+}
+
+impl Config {
+    // ファイルがなければ既定の設定
+    pub fn load() -> Config {
+        path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| Config::parse(&text))
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        let path = match path() {
+            Some(path) => path,
+            None => return,
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&path, self.format()));
+        if let Err(err) = result {
+            println!("Failed to save {}: {}", path.display(), err);
+        }
+    }
+
+    // 読めない行は無視し、範囲外の音量は上限と下限にそろえる
+    fn parse(text: &str) -> Config {
+        let mut config = Config::default();
+        for line in text.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key {
+                "volume" => config.volume = value.parse().unwrap_or(config.volume).clamp(0, MAX_VOLUME),
+                "muted" => config.muted = value.parse().unwrap_or(config.muted),
+                "equalizer" if !value.is_empty() => config.equalizer = Some(value.to_string()),
+                "preamp" => config.preamp = value.parse().ok(),
+                "sound_volume" => {
+                    config.sound_volume = value.parse().unwrap_or(config.sound_volume).clamp(0, MAX_SOUND_VOLUME)
+                }
+                // パスに = や空白が入っていてもよいように "delay=<ms> <path>"
+                "delay" => {
+                    if let Some((delay, path)) = value.split_once(' ') {
+                        if let Ok(delay) = delay.parse() {
+                            config.set_audio_delay(path, delay);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        config
+    }
+
+    pub fn audio_delay(&self, path: &str) -> i64 {
+        self.audio_delays.get(path).copied().unwrap_or(0)
+    }
+
+    pub fn set_audio_delay(&mut self, path: &str, delay: i64) {
+        if delay == 0 {
+            self.audio_delays.remove(path);
+        } else {
+            self.audio_delays.insert(path.to_string(), delay);
+        }
+    }
+
+    fn format(&self) -> String {
+        let mut text = format!(
+            "volume={}\nmuted={}\nsound_volume={}\n",
+            self.volume, self.muted, self.sound_volume
+        );
+        if let Some(equalizer) = &self.equalizer {
+            text += &format!("equalizer={}\n", equalizer);
+        }
+        if let Some(preamp) = self.preamp {
+            text += &format!("preamp={}\n", preamp);
+        }
+        for (path, delay) in &self.audio_delays {
+            text += &format!("delay={} {}\n", delay, path);
+        }
+        text
+    }
+}
+
+// Windows は %APPDATA%、それ以外は $XDG_CONFIG_HOME か ~/.config
+fn path() -> Option<PathBuf> {
+    let dir = std::env::var_os("APPDATA")
+        .or_else(|| std::env::var_os("XDG_CONFIG_HOME"))
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("opengltest").join("config.txt"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let config = Config {
+            volume: 35,
+            muted: true,
+            equalizer: Some("Large Hall".to_string()),
+            preamp: Some(-3.5),
+            audio_delays: BTreeMap::from([
+                ("C:\\videos\\a b.mp4".to_string(), -120),
+                ("https://example.com/watch?v=x".to_string(), 40),
+            ]),
+            sound_volume: 60,
+        };
+        assert_eq!(Config::parse(&config.format()), config);
+    }
+
+    #[test]
+    fn ignores_broken_lines() {
+        let config = Config::parse("volume=abc\nfoo\nmuted=true\nunknown=1\n");
+        assert_eq!(config.volume, 100);
+        assert!(config.muted);
+    }
+
+    #[test]
+    fn clamps_volumes() {
+        let config = Config::parse("volume=1000\nsound_volume=-20\n");
+        assert_eq!(config.volume, MAX_VOLUME);
+        assert_eq!(config.sound_volume, 0);
+        let config = Config::parse("volume=-5\nsound_volume=250\n");
+        assert_eq!(config.volume, 0);
+        assert_eq!(config.sound_volume, MAX_SOUND_VOLUME);
+    }
+}
@@ -0,0 +1,98 @@
+use alto::{sys, Alto, DeviceObject, OutputDevice};
+use libc::c_void;
+use std::ffi::{CStr, CString};
+
+// alcReopenDeviceSOFT (ALC_SOFT_reopen_device)
+type ReopenDevice =
+    unsafe extern "C" fn(device: *mut sys::ALCdevice, name: *const sys::ALCchar, attrs: *const sys::ALCint) -> sys::ALCboolean;
+
+// 出力デバイスの名前の一覧
+pub fn output_names(alto: &Alto) -> Vec<String> {
+    alto.enumerate_outputs()
+        .iter()
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect()
+}
+
+// 名前が一致するデバイス、なければ名前の一部が一致するデバイス
+pub fn find_output(alto: &Alto, name: &str) -> Option<CString> {
+    let outputs = alto.enumerate_outputs();
+    let lower = name.to_lowercase();
+    outputs
+        .iter()
+        .find(|output| output.to_string_lossy() == name)
+        .or_else(|| {
+            outputs
+                .iter()
+                .find(|output| output.to_string_lossy().to_lowercase().contains(&lower))
+        })
+        .cloned()
+}
+
+// name が None なら既定のデバイスを開く
+pub fn open_output(alto: &Alto, name: Option<&str>) -> Result<OutputDevice, String> {
+    let spec = match name {
+        Some(name) => Some(find_output(alto, name).ok_or_else(|| {
+            format!("Audio device \"{}\" not found\navailable devices:\n  {}", name, output_names(alto).join("\n  "))
+        })?),
+        None => None,
+    };
+    let device = alto
+        .open(spec.as_deref())
+        .map_err(|err| format!("Failed to open audio device: {}", err))?;
+    println!("audio device: {}", device_name(&device));
+    Ok(device)
+}
+
+pub fn device_name(device: &OutputDevice) -> String {
+    device
+        .specifier()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "(default)".to_string())
+}
+
+// 一覧で current の次にあるデバイス
+pub fn next_output(alto: &Alto, current: Option<&CStr>) -> Option<CString> {
+    let outputs = alto.enumerate_outputs();
+    let current = outputs
+        .iter()
+        .position(|output| Some(output.as_c_str()) == current);
+    let next = current.map(|index| (index + 1) % outputs.len()).unwrap_or(0);
+    outputs.get(next).cloned()
+}
+
+// デバイスの出力周波数 (ALC_FREQUENCY)
+pub fn device_frequency(device: &OutputDevice) -> Option<u32> {
+    let mut frequency: sys::ALCint = 0;
+    unsafe {
+        device
+            .alto()
+            .raw_api()
+            .alcGetIntegerv(device.as_raw(), sys::ALC_FREQUENCY, 1, &mut frequency);
+    }
+    if frequency > 0 {
+        Some(frequency as u32)
+    } else {
+        None
+    }
+}
+
+// コンテキストやバッファをそのままに出力先だけ切り替える
+// 拡張がなければ None
+pub fn reopen_device(device: &OutputDevice, name: Option<&CStr>) -> Option<bool> {
+    let api = device.alto().raw_api();
+    unsafe {
+        let extension = b"ALC_SOFT_reopen_device\0";
+        if api.alcIsExtensionPresent(device.as_raw(), extension.as_ptr() as *const sys::ALCchar) != sys::ALC_TRUE {
+            return None;
+        }
+        let function = b"alcReopenDeviceSOFT\0";
+        let address: *mut c_void = api.alcGetProcAddress(device.as_raw(), function.as_ptr() as *const sys::ALCchar);
+        if address.is_null() {
+            return None;
+        }
+        let reopen: ReopenDevice = std::mem::transmute(address);
+        let name = name.map(|name| name.as_ptr()).unwrap_or(std::ptr::null());
+        Some(reopen(device.as_raw(), name, std::ptr::null()) == sys::ALC_TRUE)
+    }
+}
@@ -0,0 +1,635 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+// libvlc の play コールバックから OpenAL のバッファまでの間でかける音声処理
+// サンプルは VLC の並びのインターリーブされた float
+pub trait Processor: Send {
+    // 周波数かチャンネル数が変わった (状態は捨てる)
+    fn configure(&mut self, rate: u32, channels: usize);
+    // その場で書き換える
+    fn process(&mut self, samples: &mut [f32]);
+    // flush されたとき
+    fn reset(&mut self);
+    // 出力が入力より何フレーム遅れるか
+    fn latency(&self) -> usize {
+        0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DspKind {
+    Compressor,
+    Limiter,
+    Loudness,
+}
+
+impl FromStr for DspKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<DspKind, ()> {
+        match s {
+            "compressor" => Ok(DspKind::Compressor),
+            "limiter" => Ok(DspKind::Limiter),
+            "loudness" => Ok(DspKind::Loudness),
+            _ => Err(()),
+        }
+    }
+}
+
+// 指定された順に関わらずラウドネス → コンプレッサー → リミッターの順にかける
+// リミッターは音量を掛けたあとの最後に limit でかける
+#[derive(Default)]
+pub struct Chain {
+    processors: Vec<Box<dyn Processor>>,
+    limiter: Option<Limiter>,
+}
+
+impl Chain {
+    pub fn new(kinds: &[DspKind]) -> Chain {
+        let mut chain = Chain::default();
+        if kinds.contains(&DspKind::Loudness) {
+            chain.push(Box::new(LoudnessNormalizer::default()));
+        }
+        if kinds.contains(&DspKind::Compressor) {
+            chain.push(Box::new(Compressor::default()));
+        }
+        if kinds.contains(&DspKind::Limiter) {
+            chain.limiter = Some(Limiter::default());
+        }
+        chain
+    }
+
+    pub fn push(&mut self, processor: Box<dyn Processor>) {
+        self.processors.push(processor);
+    }
+
+    fn all(&mut self) -> impl Iterator<Item = &mut (dyn Processor + 'static)> {
+        let limiter = self.limiter.as_mut().map(|limiter| limiter as &mut (dyn Processor + 'static));
+        self.processors.iter_mut().map(|processor| processor.as_mut()).chain(limiter)
+    }
+
+    pub fn configure(&mut self, rate: u32, channels: usize) {
+        for processor in self.all() {
+            processor.configure(rate, channels);
+        }
+    }
+
+    // リミッターの手前まで
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for processor in self.processors.iter_mut() {
+            processor.process(samples);
+        }
+    }
+
+    pub fn limit(&mut self, samples: &mut [f32]) {
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.process(samples);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for processor in self.all() {
+            processor.reset();
+        }
+    }
+
+    pub fn latency(&self) -> usize {
+        let limiter = self.limiter.as_ref().map_or(0, |limiter| limiter.latency());
+        self.processors.iter().map(|processor| processor.latency()).sum::<usize>() + limiter
+    }
+}
+
+fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+fn gain_to_db(gain: f64) -> f64 {
+    20.0 * gain.max(1e-9).log10()
+}
+
+// time_ms でおよそ 63% 近づく一次の平滑化の係数
+fn smoothing(time_ms: f64, rate: u32) -> f64 {
+    (-1000.0 / (time_ms * rate as f64)).exp()
+}
+
+// フィードフォワードのコンプレッサー
+// 全チャンネルのピークで同じだけ下げるので定位は変わらない
+pub struct Compressor {
+    pub threshold_db: f64,
+    pub ratio: f64,
+    pub attack_ms: f64,
+    pub release_ms: f64,
+    pub makeup_db: f64,
+    channels: usize,
+    attack: f64,
+    release: f64,
+    // 波形の山と山の間で下がらないようにピークを保持した値
+    level: f64,
+    // いまのゲインリダクション (dB)
+    reduction: f64,
+}
+
+impl Default for Compressor {
+    fn default() -> Compressor {
+        let mut compressor = Compressor {
+            threshold_db: -24.0,
+            ratio: 3.0,
+            attack_ms: 10.0,
+            release_ms: 200.0,
+            makeup_db: 0.0,
+            channels: 2,
+            attack: 0.0,
+            release: 0.0,
+            level: 0.0,
+            reduction: 0.0,
+        };
+        compressor.configure(48000, 2);
+        compressor
+    }
+}
+
+impl Processor for Compressor {
+    fn configure(&mut self, rate: u32, channels: usize) {
+        self.channels = channels;
+        self.attack = smoothing(self.attack_ms, rate);
+        self.release = smoothing(self.release_ms, rate);
+        self.reset();
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_mut(self.channels) {
+            let peak = frame.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            self.level = (peak as f64).max(self.level * self.release);
+            let over = gain_to_db(self.level) - self.threshold_db;
+            let target = if over > 0.0 { over * (1.0 - 1.0 / self.ratio) } else { 0.0 };
+            let coefficient = if target > self.reduction { self.attack } else { self.release };
+            self.reduction = target + (self.reduction - target) * coefficient;
+
+            let gain = db_to_gain(self.makeup_db - self.reduction) as f32;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.level = 0.0;
+        self.reduction = 0.0;
+    }
+}
+
+// 先読みするブリックウォールリミッター
+// 先読みの間にゲインを下げきるので ceiling を超えない
+pub struct Limiter {
+    pub ceiling_db: f64,
+    pub lookahead_ms: f64,
+    pub release_ms: f64,
+    channels: usize,
+    lookahead: usize,
+    release: f32,
+    ceiling: f32,
+    delay: VecDeque<f32>,
+    // (フレーム番号, そのフレームに必要なゲイン) の単調増加の列
+    window: VecDeque<(u64, f32)>,
+    frame: u64,
+    gain: f32,
+    // 先読みが間に合わずに切り詰めたサンプル数
+    clipped: u64,
+}
+
+impl Default for Limiter {
+    fn default() -> Limiter {
+        let mut limiter = Limiter {
+            ceiling_db: -1.0,
+            lookahead_ms: 5.0,
+            release_ms: 50.0,
+            channels: 2,
+            lookahead: 0,
+            release: 0.0,
+            ceiling: 1.0,
+            delay: VecDeque::new(),
+            window: VecDeque::new(),
+            frame: 0,
+            gain: 1.0,
+            clipped: 0,
+        };
+        limiter.configure(48000, 2);
+        limiter
+    }
+}
+
+impl Processor for Limiter {
+    fn configure(&mut self, rate: u32, channels: usize) {
+        self.channels = channels;
+        self.lookahead = (self.lookahead_ms * rate as f64 / 1000.0).round().max(1.0) as usize;
+        self.release = smoothing(self.release_ms, rate) as f32;
+        self.ceiling = db_to_gain(self.ceiling_db) as f32;
+        self.reset();
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        let lookahead = self.lookahead as u64;
+        for frame in samples.chunks_mut(self.channels) {
+            let peak = frame.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            let required = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+
+            // 先読みの範囲で一番小さいゲイン
+            while self.window.back().is_some_and(|&(_, gain)| gain >= required) {
+                self.window.pop_back();
+            }
+            self.window.push_back((self.frame, required));
+            while self.window.front().is_some_and(|&(frame, _)| frame + lookahead < self.frame) {
+                self.window.pop_front();
+            }
+            let target = self.window.front().map_or(1.0, |&(_, gain)| gain);
+            self.gain = if target < self.gain {
+                target
+            } else {
+                target + (self.gain - target) * self.release
+            };
+            self.frame += 1;
+
+            for sample in frame.iter_mut() {
+                self.delay.push_back(*sample);
+                let delayed = self.delay.pop_front().unwrap_or(0.0);
+                let limited = delayed * self.gain;
+                // 丸め誤差より大きくはみ出したら先読みの不具合
+                if limited.abs() > self.ceiling * (1.0 + 1e-5) {
+                    self.clipped += 1;
+                }
+                *sample = limited.clamp(-self.ceiling, self.ceiling);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.delay.clear();
+        self.delay.resize(self.lookahead * self.channels, 0.0);
+        self.window.clear();
+        self.frame = 0;
+        self.gain = 1.0;
+    }
+
+    fn latency(&self) -> usize {
+        self.lookahead
+    }
+}
+
+// ITU-R BS.1770 の K 特性フィルター (シェルフ + ハイパス) の双二次フィルター
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+}
+
+impl Biquad {
+    // libebur128 と同じく任意の周波数で係数を求める
+    fn k_weighting(rate: u32) -> [Biquad; 2] {
+        let rate = rate as f64;
+
+        let f0 = 1681.974450955533;
+        let g = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        };
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let highpass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        };
+        [shelf, highpass]
+    }
+
+    // state は直接形 II の 2 つの遅延
+    fn process(&self, state: &mut [f64; 2], x: f64) -> f64 {
+        let w = x - self.a[1] * state[0] - self.a[2] * state[1];
+        let y = self.b[0] * w + self.b[1] * state[0] + self.b[2] * state[1];
+        state[1] = state[0];
+        state[0] = w;
+        y
+    }
+}
+
+// 100ms ごとのブロックで 400ms の平均二乗を求める
+const SUB_BLOCK_MS: u32 = 100;
+const SUB_BLOCKS: usize = 4;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+// BS.1770 のゲート付きラウドネスの計測
+// 曲が変わったら追従できるように直近 history 個のブロックだけで求める
+pub struct LoudnessMeter {
+    channels: usize,
+    filters: [Biquad; 2],
+    states: Vec<[[f64; 2]; 2]>,
+    sub_block_frames: usize,
+    frames: usize,
+    energy: f64,
+    sub_blocks: VecDeque<f64>,
+    // 400ms ブロックの平均二乗
+    blocks: VecDeque<f64>,
+    history: usize,
+}
+
+impl LoudnessMeter {
+    pub fn new(rate: u32, channels: usize, history_ms: u32) -> LoudnessMeter {
+        LoudnessMeter {
+            channels,
+            filters: Biquad::k_weighting(rate),
+            states: vec![[[0.0; 2]; 2]; channels],
+            sub_block_frames: (rate * SUB_BLOCK_MS / 1000) as usize,
+            frames: 0,
+            energy: 0.0,
+            sub_blocks: VecDeque::with_capacity(SUB_BLOCKS),
+            blocks: VecDeque::new(),
+            history: (history_ms / SUB_BLOCK_MS) as usize,
+        }
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            // チャンネルの重みはすべて 1.0 (サラウンドの 1.41 は省略)
+            for (sample, state) in frame.iter().zip(self.states.iter_mut()) {
+                let y = self.filters[0].process(&mut state[0], *sample as f64);
+                let y = self.filters[1].process(&mut state[1], y);
+                self.energy += y * y;
+            }
+            self.frames += 1;
+            if self.frames == self.sub_block_frames {
+                self.finish_sub_block();
+            }
+        }
+    }
+
+    fn finish_sub_block(&mut self) {
+        if self.sub_blocks.len() == SUB_BLOCKS {
+            self.sub_blocks.pop_front();
+        }
+        self.sub_blocks.push_back(self.energy / self.frames as f64);
+        self.energy = 0.0;
+        self.frames = 0;
+        // 75% 重ねた 400ms のブロック
+        if self.sub_blocks.len() == SUB_BLOCKS {
+            if self.blocks.len() == self.history {
+                self.blocks.pop_front();
+            }
+            self.blocks.push_back(self.sub_blocks.iter().sum::<f64>() / SUB_BLOCKS as f64);
+        }
+    }
+
+    // ゲートを通るブロックがなければ None (無音など)
+    pub fn loudness(&self) -> Option<f64> {
+        let absolute: Vec<f64> = self
+            .blocks
+            .iter()
+            .copied()
+            .filter(|&power| loudness(power) > ABSOLUTE_GATE)
+            .collect();
+        if absolute.is_empty() {
+            return None;
+        }
+        let gate = loudness(absolute.iter().sum::<f64>() / absolute.len() as f64) + RELATIVE_GATE;
+        let relative: Vec<f64> = absolute.into_iter().filter(|&power| loudness(power) > gate).collect();
+        if relative.is_empty() {
+            return None;
+        }
+        Some(loudness(relative.iter().sum::<f64>() / relative.len() as f64))
+    }
+
+    pub fn reset(&mut self) {
+        for state in self.states.iter_mut() {
+            *state = [[0.0; 2]; 2];
+        }
+        self.frames = 0;
+        self.energy = 0.0;
+        self.sub_blocks.clear();
+        self.blocks.clear();
+    }
+}
+
+// 平均二乗 (チャンネルの和) から LUFS
+fn loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.max(1e-20).log10()
+}
+
+// EBU R128 風のラウドネスノーマライザー
+// 直近のラウドネスを target_lufs に合わせるゲインにゆっくり近づける
+pub struct LoudnessNormalizer {
+    pub target_lufs: f64,
+    pub max_boost_db: f64,
+    pub max_cut_db: f64,
+    // 1 秒あたりにゲインを変える最大量 (dB)
+    pub rate_db: f64,
+    pub history_ms: u32,
+    channels: usize,
+    rate: u32,
+    meter: LoudnessMeter,
+    gain_db: f64,
+}
+
+impl Default for LoudnessNormalizer {
+    fn default() -> LoudnessNormalizer {
+        LoudnessNormalizer {
+            target_lufs: -16.0,
+            max_boost_db: 12.0,
+            max_cut_db: 20.0,
+            rate_db: 3.0,
+            history_ms: 10_000,
+            channels: 2,
+            rate: 48000,
+            meter: LoudnessMeter::new(48000, 2, 10_000),
+            gain_db: 0.0,
+        }
+    }
+}
+
+impl Processor for LoudnessNormalizer {
+    fn configure(&mut self, rate: u32, channels: usize) {
+        self.rate = rate;
+        self.channels = channels;
+        self.meter = LoudnessMeter::new(rate, channels, self.history_ms);
+        self.gain_db = 0.0;
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        // 入力を測ってから、このパケットの間にゲインを近づける
+        self.meter.push(samples);
+        let target = match self.meter.loudness() {
+            Some(loudness) => (self.target_lufs - loudness).clamp(-self.max_cut_db, self.max_boost_db),
+            None => self.gain_db,
+        };
+        let step = self.rate_db / self.rate as f64;
+        for frame in samples.chunks_mut(self.channels) {
+            self.gain_db += (target - self.gain_db).clamp(-step, step);
+            let gain = db_to_gain(self.gain_db) as f32;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+
+    // シークしても同じ曲なのでゲインはそのまま
+    fn reset(&mut self) {
+        self.meter.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    // ステレオの正弦波、amplitude はピーク
+    fn sine(freq: f64, amplitude: f64, seconds: f64) -> Vec<f32> {
+        let frames = (seconds * RATE as f64) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let sample = (amplitude * (2.0 * PI * freq * i as f64 / RATE as f64).sin()) as f32;
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    fn peak_db(samples: &[f32]) -> f64 {
+        gain_to_db(samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs())) as f64)
+    }
+
+    // 最後の seconds 秒
+    fn tail(samples: &[f32], seconds: f64) -> &[f32] {
+        let len = (seconds * RATE as f64) as usize * 2;
+        &samples[samples.len() - len..]
+    }
+
+    #[test]
+    fn compressor_reduces_above_threshold() {
+        let mut compressor = Compressor {
+            threshold_db: -20.0,
+            ratio: 4.0,
+            ..Default::default()
+        };
+        compressor.configure(RATE, 2);
+        let mut samples = sine(1000.0, db_to_gain(-6.0), 2.0);
+        compressor.process(&mut samples);
+        // 14dB 超えているので 4:1 なら 3.5dB 超えまで下がる
+        let level = peak_db(tail(&samples, 0.5));
+        assert!((level + 16.5).abs() < 0.5, "{}", level);
+    }
+
+    #[test]
+    fn compressor_passes_quiet_signal() {
+        let mut compressor = Compressor {
+            threshold_db: -20.0,
+            ..Default::default()
+        };
+        compressor.configure(RATE, 2);
+        let input = sine(1000.0, db_to_gain(-30.0), 1.0);
+        let mut samples = input.clone();
+        compressor.process(&mut samples);
+        assert_eq!(samples, input);
+    }
+
+    #[test]
+    fn limiter_never_exceeds_ceiling() {
+        let mut limiter = Limiter::default();
+        limiter.configure(RATE, 2);
+        let mut samples = sine(100.0, 4.0, 0.5);
+        // 急に大きくなる音も先読みで抑える
+        samples.extend(sine(3000.0, 10.0, 0.1));
+        // 先読みより短い破裂音は出てくるまでゲインを保つ
+        let silence = vec![0.0; RATE as usize / 10 * 2];
+        samples.extend(&silence);
+        samples.extend(sine(3000.0, 10.0, 0.001));
+        samples.extend(&silence);
+        limiter.process(&mut samples);
+        // 最後の clamp で切り詰めずにゲインだけで収まっている
+        assert_eq!(limiter.clipped, 0);
+        let ceiling = db_to_gain(-1.0) as f32;
+        assert!(samples.iter().all(|sample| sample.abs() <= ceiling + 1e-6));
+    }
+
+    #[test]
+    fn limiter_delays_quiet_signal_unchanged() {
+        let mut limiter = Limiter::default();
+        limiter.configure(RATE, 2);
+        let input = sine(440.0, 0.5, 0.2);
+        let mut samples = input.clone();
+        limiter.process(&mut samples);
+        let delay = limiter.latency() * 2;
+        assert!(samples[..delay].iter().all(|&sample| sample == 0.0));
+        assert_eq!(&samples[delay..], &input[..input.len() - delay]);
+    }
+
+    #[test]
+    fn meter_reads_sine_level() {
+        // 1kHz の正弦波を両チャンネルに入れると、ピークの dBFS とほぼ同じ LUFS になる
+        let mut meter = LoudnessMeter::new(RATE, 2, 10_000);
+        meter.push(&sine(1000.0, db_to_gain(-23.0), 5.0));
+        let loudness = meter.loudness().unwrap();
+        assert!((loudness + 23.0).abs() < 0.3, "{}", loudness);
+    }
+
+    #[test]
+    fn meter_ignores_silence() {
+        let mut meter = LoudnessMeter::new(RATE, 2, 10_000);
+        meter.push(&vec![0.0; RATE as usize * 2]);
+        assert_eq!(meter.loudness(), None);
+    }
+
+    #[test]
+    fn normalizer_reaches_target() {
+        let mut normalizer = LoudnessNormalizer::default();
+        normalizer.configure(RATE, 2);
+        let mut samples = sine(1000.0, db_to_gain(-26.0), 8.0);
+        normalizer.process(&mut samples);
+        // -26 LUFS を -16 LUFS まで上げる
+        let level = peak_db(tail(&samples, 1.0));
+        assert!((level + 16.0).abs() < 0.5, "{}", level);
+    }
+
+    #[test]
+    fn normalizer_follows_in_small_packets() {
+        let mut normalizer = LoudnessNormalizer::default();
+        normalizer.configure(RATE, 2);
+        let mut samples = sine(1000.0, db_to_gain(-10.0), 8.0);
+        for chunk in samples.chunks_mut(1920) {
+            normalizer.process(chunk);
+        }
+        let level = peak_db(tail(&samples, 1.0));
+        assert!((level + 16.0).abs() < 0.5, "{}", level);
+    }
+
+    #[test]
+    fn chain_sums_latency() {
+        let mut chain = Chain::new(&[DspKind::Compressor, DspKind::Limiter, DspKind::Loudness]);
+        chain.configure(RATE, 2);
+        assert_eq!(chain.latency(), 240);
+    }
+
+    #[test]
+    fn chain_limits_after_volume() {
+        // 並びを逆に指定してもリミッターは最後
+        let mut chain = Chain::new(&[DspKind::Limiter, DspKind::Compressor, DspKind::Loudness]);
+        chain.configure(RATE, 2);
+        let mut samples = sine(1000.0, db_to_gain(-10.0), 2.0);
+        chain.process(&mut samples);
+        // 音量 200% 相当
+        for sample in samples.iter_mut() {
+            *sample *= 8.0;
+        }
+        assert!(peak_db(&samples) > 0.0);
+        chain.limit(&mut samples);
+        assert!(peak_db(&samples) <= -1.0 + 1e-3, "{}", peak_db(&samples));
+    }
+}
@@ -0,0 +1,91 @@
+use alto::efx::{self, AuxEffectSlot, EaxReverbEffect, EaxReverbProperties, ReverbEffect};
+use alto::{ext, Context, ContextAttrs, DeviceObject, OutputDevice, SoftHrtfStatus};
+
+// HRTF とリバーブの設定
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EffectConfig {
+    pub hrtf: bool,
+    // REVERB_PRESETS の何番目か
+    pub reverb: Option<usize>,
+}
+
+// R キーで切り替えるリバーブ
+pub const REVERB_PRESETS: &[(&str, EaxReverbProperties)] = &[
+    ("generic", efx::REVERB_PRESET_GENERIC),
+    ("room", efx::REVERB_PRESET_ROOM),
+    ("livingroom", efx::REVERB_PRESET_LIVINGROOM),
+    ("bathroom", efx::REVERB_PRESET_BATHROOM),
+    ("stoneroom", efx::REVERB_PRESET_STONEROOM),
+    ("hallway", efx::REVERB_PRESET_HALLWAY),
+    ("auditorium", efx::REVERB_PRESET_AUDITORIUM),
+    ("concerthall", efx::REVERB_PRESET_CONCERTHALL),
+    ("cave", efx::REVERB_PRESET_CAVE),
+    ("arena", efx::REVERB_PRESET_ARENA),
+    ("hangar", efx::REVERB_PRESET_HANGAR),
+    ("underwater", efx::REVERB_PRESET_UNDERWATER),
+];
+
+pub fn find_reverb(name: &str) -> Option<usize> {
+    REVERB_PRESETS.iter().position(|(preset, _)| *preset == name)
+}
+
+pub fn reverb_name(preset: Option<usize>) -> &'static str {
+    preset.map(|index| REVERB_PRESETS[index].0).unwrap_or("off")
+}
+
+// なし → 1 番目 → ... → 最後 → なし
+pub fn next_reverb(preset: Option<usize>) -> Option<usize> {
+    match preset {
+        None => Some(0),
+        Some(index) if index + 1 < REVERB_PRESETS.len() => Some(index + 1),
+        Some(_) => None,
+    }
+}
+
+// ALC_SOFT_HRTF がなければ None (属性を渡さない)
+pub fn hrtf_attrs(device: &OutputDevice, enabled: bool) -> Option<ContextAttrs> {
+    if !device.is_extension_present(ext::Alc::SoftHrtf) {
+        return None;
+    }
+    Some(ContextAttrs {
+        soft_hrtf: Some(enabled),
+        ..Default::default()
+    })
+}
+
+// 要求した HRTF の状態と実際に使われているかをログに出す
+pub fn report_hrtf(device: &OutputDevice, enabled: bool) {
+    if !device.is_extension_present(ext::Alc::SoftHrtf) {
+        println!("HRTF: not supported by this device");
+        return;
+    }
+    let status = device.soft_hrtf_status();
+    let active = matches!(status, SoftHrtfStatus::Enabled | SoftHrtfStatus::Required | SoftHrtfStatus::HeadphonesDetected);
+    println!(
+        "HRTF: requested {}, {} ({:?})",
+        if enabled { "on" } else { "off" },
+        if active { "active" } else { "inactive" },
+        status
+    );
+}
+
+// リバーブを設定したエフェクトスロット
+// EAX リバーブがなければ標準のリバーブを使う
+pub fn reverb_slot(context: &Context, preset: &EaxReverbProperties) -> Result<AuxEffectSlot, String> {
+    if !context.device().is_extension_present(ext::Alc::Efx) {
+        return Err("EFX is not supported by this device".to_string());
+    }
+    let mut slot = context.new_aux_effect_slot().map_err(|err| err.to_string())?;
+    match context.new_effect::<EaxReverbEffect>() {
+        Ok(mut effect) => {
+            effect.set_preset(preset).map_err(|err| err.to_string())?;
+            slot.set_effect(&effect).map_err(|err| err.to_string())?;
+        }
+        Err(_) => {
+            let mut effect = context.new_effect::<ReverbEffect>().map_err(|err| err.to_string())?;
+            effect.set_preset(preset).map_err(|err| err.to_string())?;
+            slot.set_effect(&effect).map_err(|err| err.to_string())?;
+        }
+    }
+    Ok(slot)
+}
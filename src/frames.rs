@@ -0,0 +1,475 @@
+use libc::c_void;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use crate::support::{ColorMatrix, ColorSpace, MappedPlanes, PixelFormat};
+
+// VLC が書き込み中、表示待ち、描画スレッドが転送中
+pub const SLOTS: usize = 3;
+
+// スロットの状態
+const FREE: u8 = 0;
+// lock で VLC に渡した
+const WRITING: u8 = 1;
+// unlock も display もされた
+const READY: u8 = 3;
+// 描画スレッドが使っている
+const READING: u8 = 4;
+
+// WRITING の間に VLC から呼ばれたコールバック
+// VLC のバージョンによって unlock と display の順番が違うので、両方そろったら READY にする
+const UNLOCKED: usize = 1;
+const DISPLAYED: usize = 2;
+
+// lock が返す id の下位ビットはスロットの番号、残りは lock した世代
+// 使い直したスロットに前の世代の unlock や display が来ても無視できる
+const INDEX_BITS: u32 = 4;
+
+// lock で空きがなかったときの id
+pub const NO_SLOT: usize = usize::MAX;
+
+// id を (スロットの番号, 世代) に分ける
+fn split_id(id: usize) -> (usize, usize) {
+    (id & ((1 << INDEX_BITS) - 1), id >> INDEX_BITS)
+}
+
+// marks の世代 (id に入る分だけ)
+fn generation(marks: usize) -> usize {
+    (marks >> 2) & (usize::MAX >> INDEX_BITS)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameFormat {
+    pub pixel_format: PixelFormat,
+    pub width: u32,
+    pub height: u32,
+    pub color_space: ColorSpace,
+}
+
+impl FrameFormat {
+    // AtomicU64 に入れるため 1 つの整数にまとめる (0 はフォーマットなし)
+    // 幅と高さは 24 ビットずつ
+    fn pack(&self) -> u64 {
+        let pixel_format = match self.pixel_format {
+            PixelFormat::Rgb24 => 0,
+            PixelFormat::I420 => 1,
+            PixelFormat::Nv12 => 2,
+        };
+        let matrix = match self.color_space.matrix {
+            ColorMatrix::Bt601 => 0,
+            ColorMatrix::Bt709 => 1,
+            ColorMatrix::Bt2020 => 2,
+        };
+        1 | (self.color_space.full_range as u64) << 1
+            | matrix << 2
+            | pixel_format << 4
+            | ((self.width & 0xff_ffff) as u64) << 8
+            | ((self.height & 0xff_ffff) as u64) << 32
+    }
+
+    fn unpack(value: u64) -> Option<FrameFormat> {
+        if value & 1 == 0 {
+            return None;
+        }
+        let matrix = match (value >> 2) & 3 {
+            0 => ColorMatrix::Bt601,
+            1 => ColorMatrix::Bt709,
+            _ => ColorMatrix::Bt2020,
+        };
+        let pixel_format = match (value >> 4) & 15 {
+            0 => PixelFormat::Rgb24,
+            1 => PixelFormat::I420,
+            _ => PixelFormat::Nv12,
+        };
+        Some(FrameFormat {
+            pixel_format,
+            width: ((value >> 8) & 0xff_ffff) as u32,
+            height: ((value >> 32) & 0xff_ffff) as u32,
+            color_space: ColorSpace {
+                matrix,
+                full_range: (value >> 1) & 1 != 0,
+            },
+        })
+    }
+}
+
+pub struct Frame {
+    pub format: Option<FrameFormat>,
+    // PBO が使えないときの書き込み先
+    pub planes: [Vec<u8>; 3],
+    // 描画スレッドが用意した PBO の書き込み先と、そのときのフォーマット
+    pub mapped: Option<MappedPlanes>,
+    pub mapped_format: Option<FrameFormat>,
+    // 最後に mapped に書き込んだか
+    pub in_pbo: bool,
+}
+
+impl Frame {
+    fn new() -> Frame {
+        Frame {
+            format: None,
+            planes: [Vec::new(), Vec::new(), Vec::new()],
+            mapped: None,
+            mapped_format: None,
+            in_pbo: false,
+        }
+    }
+
+    // format のフレームを書き込めるようにしてプレーンの先頭を返す
+    fn prepare(&mut self, format: Option<FrameFormat>) -> [*mut c_void; 3] {
+        self.format = format;
+        self.in_pbo = format.is_some() && self.mapped.is_some() && self.mapped_format == format;
+        if self.in_pbo {
+            return self.mapped.unwrap().0;
+        }
+        let planes = format.map_or(Vec::new(), |format| format.pixel_format.planes(format.width, format.height));
+        let mut pointers = [std::ptr::null_mut(); 3];
+        for (i, (pointer, buffer)) in pointers.iter_mut().zip(self.planes.iter_mut()).enumerate() {
+            let (plane_width, plane_height, bpp) = planes.get(i).copied().unwrap_or((0, 0, 0));
+            buffer.resize((plane_width * bpp * plane_height) as usize, 0);
+            *pointer = buffer.as_mut_ptr() as *mut c_void;
+        }
+        pointers
+    }
+}
+
+struct Slot {
+    state: AtomicU8,
+    // (世代 << 2) | UNLOCKED | DISPLAYED
+    marks: AtomicUsize,
+    // display された順番 (新しいほど大きい)
+    sequence: AtomicU64,
+    // display が呼ばれた時刻 (libvlc_clock)
+    pts: AtomicI64,
+    // state を WRITING か READING にしたスレッドだけが触る
+    frame: UnsafeCell<Frame>,
+}
+
+// VLC のコールバックと描画スレッドの間で受け渡すフレームのキュー
+// どちらもロックせず、スロットの状態を compare_exchange で取り合う
+pub struct FrameQueue {
+    slots: Vec<Slot>,
+    format: AtomicU64,
+    next_sequence: AtomicU64,
+    // 空きがなかったときに書き込ませて捨てる
+    scratch: UnsafeCell<Frame>,
+}
+
+unsafe impl Sync for FrameQueue {}
+unsafe impl Send for FrameQueue {}
+
+impl FrameQueue {
+    pub fn new() -> FrameQueue {
+        FrameQueue {
+            slots: (0..SLOTS)
+                .map(|_| Slot {
+                    state: AtomicU8::new(FREE),
+                    marks: AtomicUsize::new(0),
+                    sequence: AtomicU64::new(0),
+                    pts: AtomicI64::new(0),
+                    frame: UnsafeCell::new(Frame::new()),
+                })
+                .collect(),
+            format: AtomicU64::new(0),
+            next_sequence: AtomicU64::new(1),
+            scratch: UnsafeCell::new(Frame::new()),
+        }
+    }
+
+    // format コールバックで設定し、cleanup で None に戻す
+    pub fn set_format(&self, format: Option<FrameFormat>) {
+        self.format.store(format.map_or(0, |format| format.pack()), Ordering::Release);
+    }
+
+    pub fn format(&self) -> Option<FrameFormat> {
+        FrameFormat::unpack(self.format.load(Ordering::Acquire))
+    }
+
+    fn transition(&self, index: usize, from: u8, to: u8) -> bool {
+        self.slots[index]
+            .state
+            .compare_exchange(from, to, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    // lock コールバック: 空いているスロットか、一番古い表示待ちのフレームのスロットに書き込ませる
+    // 返す id を VLC のピクチャーとして unlock と display に渡す
+    pub fn lock(&self) -> (usize, [*mut c_void; 3]) {
+        let oldest_ready = || {
+            (0..SLOTS)
+                .filter(|&index| self.slots[index].state.load(Ordering::Acquire) == READY)
+                .min_by_key(|&index| self.slots[index].sequence.load(Ordering::Relaxed))
+        };
+        let claimed = (0..SLOTS)
+            .find(|&index| self.transition(index, FREE, WRITING))
+            .or_else(|| oldest_ready().filter(|&index| self.transition(index, READY, WRITING)))
+            .map(|index| {
+                let marks = &self.slots[index].marks;
+                let generation = (marks.load(Ordering::Relaxed) >> 2).wrapping_add(1);
+                marks.store(generation << 2, Ordering::Release);
+                (index, generation)
+            })
+            .or_else(|| self.reclaim_dropped());
+
+        match claimed {
+            Some((index, generation)) => {
+                let planes = unsafe { (*self.slots[index].frame.get()).prepare(self.format()) };
+                (generation << INDEX_BITS | index, planes)
+            }
+            None => (NO_SLOT, unsafe { (*self.scratch.get()).prepare(self.format()) }),
+        }
+    }
+
+    // unlock されたが display されていないスロット (VLC が捨てたフレーム) を古い順に使い直す
+    fn reclaim_dropped(&self) -> Option<(usize, usize)> {
+        let mut dropped: Vec<(usize, usize)> = (0..SLOTS)
+            .filter(|&index| self.slots[index].state.load(Ordering::Acquire) == WRITING)
+            .map(|index| (index, self.slots[index].marks.load(Ordering::Acquire)))
+            .filter(|(_, marks)| marks & (UNLOCKED | DISPLAYED) == UNLOCKED)
+            .collect();
+        dropped.sort_by_key(|(_, marks)| marks >> 2);
+        dropped.into_iter().find_map(|(index, marks)| {
+            let generation = (marks >> 2).wrapping_add(1);
+            self.slots[index]
+                .marks
+                .compare_exchange(marks, generation << 2, Ordering::AcqRel, Ordering::Acquire)
+                .ok()
+                .map(|_| (index, generation))
+        })
+    }
+
+    // id のスロットがまだその世代なら flag を立て、unlock と display がそろえば描画スレッドに渡す
+    fn mark(&self, id: usize, flag: usize) {
+        if id == NO_SLOT {
+            return;
+        }
+        let (index, id_generation) = split_id(id);
+        let slot = &self.slots[index];
+        let mut marks = slot.marks.load(Ordering::Acquire);
+        loop {
+            if generation(marks) != id_generation || marks & flag != 0 {
+                return;
+            }
+            match slot.marks.compare_exchange_weak(marks, marks | flag, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(current) => marks = current,
+            }
+        }
+        if (marks | flag) & (UNLOCKED | DISPLAYED) == UNLOCKED | DISPLAYED {
+            self.transition(index, WRITING, READY);
+        }
+    }
+
+    // unlock コールバック: VLC がもう書き込まない
+    pub fn unlock(&self, id: usize) {
+        self.mark(id, UNLOCKED);
+    }
+
+    // display コールバック: 表示する時刻になった
+    pub fn display(&self, id: usize, pts: i64) {
+        if id == NO_SLOT {
+            return;
+        }
+        let (index, id_generation) = split_id(id);
+        let slot = &self.slots[index];
+        // 使い直されたスロットの pts は書き換えない
+        if generation(slot.marks.load(Ordering::Acquire)) != id_generation {
+            return;
+        }
+        slot.pts.store(pts, Ordering::Relaxed);
+        slot.sequence.store(self.next_sequence.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        self.mark(id, DISPLAYED);
+    }
+
+    // pts で due が true を返すフレームのうち一番新しいものを描画スレッドのものにする
+    // それより古いフレームは捨て、まだ表示する時刻になっていないフレームは残す
+    pub fn take_newest(&self, due: impl Fn(i64) -> bool) -> Option<usize> {
+        let newest = (0..SLOTS)
+            .filter(|&index| self.slots[index].state.load(Ordering::Acquire) == READY)
+            .filter(|&index| due(self.slots[index].pts.load(Ordering::Relaxed)))
+            .max_by_key(|&index| self.slots[index].sequence.load(Ordering::Relaxed))?;
+        if !self.transition(newest, READY, READING) {
+            return None;
+        }
+        let sequence = self.slots[newest].sequence.load(Ordering::Relaxed);
+        for index in 0..SLOTS {
+            if self.slots[index].sequence.load(Ordering::Relaxed) < sequence {
+                self.transition(index, READY, FREE);
+            }
+        }
+        Some(newest)
+    }
+
+    // まだ渡していないフレームがある (表示する時刻になっていない)
+    pub fn pending(&self) -> bool {
+        self.slots.iter().any(|slot| slot.state.load(Ordering::Acquire) == READY)
+    }
+
+    // take_newest で受け取ったスロットのフレーム
+    // release するまでは VLC は書き込まない
+    pub fn frame(&self, index: usize) -> &Frame {
+        assert_eq!(self.slots[index].state.load(Ordering::Acquire), READING);
+        unsafe { &*self.slots[index].frame.get() }
+    }
+
+    pub fn release(&self, index: usize) {
+        self.transition(index, READING, FREE);
+    }
+
+    // 空いていれば描画スレッドのものにして f を呼ぶ (PBO を作り直すときなど)
+    pub fn with_free(&self, index: usize, f: impl FnOnce(&mut Frame)) -> bool {
+        if !self.transition(index, FREE, READING) {
+            return false;
+        }
+        f(unsafe { &mut *self.slots[index].frame.get() });
+        self.release(index);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(width: u32, height: u32) -> FrameFormat {
+        FrameFormat {
+            pixel_format: PixelFormat::I420,
+            width,
+            height,
+            color_space: ColorSpace {
+                matrix: ColorMatrix::Bt709,
+                full_range: true,
+            },
+        }
+    }
+
+    // lock して先頭のバイトに value を書き込む
+    fn write(queue: &FrameQueue, value: u8) -> usize {
+        let (id, planes) = queue.lock();
+        unsafe {
+            *(planes[0] as *mut u8) = value;
+        }
+        id
+    }
+
+    fn decode(queue: &FrameQueue, value: u8, pts: i64) {
+        let id = write(queue, value);
+        queue.unlock(id);
+        queue.display(id, pts);
+    }
+
+    #[test]
+    fn packs_format() {
+        let format = format(7680, 4320);
+        assert_eq!(FrameFormat::unpack(format.pack()), Some(format));
+        assert_eq!(FrameFormat::unpack(0), None);
+    }
+
+    #[test]
+    fn renderer_gets_newest_frame() {
+        let queue = FrameQueue::new();
+        queue.set_format(Some(format(4, 2)));
+        decode(&queue, 1, 100);
+        decode(&queue, 2, 200);
+        let index = queue.take_newest(|_| true).unwrap();
+        assert_eq!(queue.frame(index).planes[0][0], 2);
+        assert_eq!(queue.frame(index).format, Some(format(4, 2)));
+        // 古いフレームは捨てられている
+        queue.release(index);
+        assert_eq!(queue.take_newest(|_| true), None);
+    }
+
+    #[test]
+    fn waits_until_frame_is_due() {
+        let queue = FrameQueue::new();
+        queue.set_format(Some(format(4, 2)));
+        decode(&queue, 1, 100);
+        assert_eq!(queue.take_newest(|pts| pts <= 50), None);
+        assert!(queue.pending());
+        assert!(queue.take_newest(|pts| pts <= 100).is_some());
+        assert!(!queue.pending());
+    }
+
+    #[test]
+    fn takes_older_frame_when_newest_is_not_due() {
+        let queue = FrameQueue::new();
+        queue.set_format(Some(format(4, 2)));
+        decode(&queue, 1, 100);
+        decode(&queue, 2, 200);
+        // 音声が 2 つのフレームの間にいるときは古い方を表示する
+        let index = queue.take_newest(|pts| pts <= 150).unwrap();
+        assert_eq!(queue.frame(index).planes[0][0], 1);
+        queue.release(index);
+        // 新しい方は時刻になるまで残っている
+        assert!(queue.pending());
+        assert_eq!(queue.take_newest(|pts| pts <= 150), None);
+        let index = queue.take_newest(|pts| pts <= 200).unwrap();
+        assert_eq!(queue.frame(index).planes[0][0], 2);
+    }
+
+    #[test]
+    fn never_overwrites_frame_being_read() {
+        let queue = FrameQueue::new();
+        queue.set_format(Some(format(4, 2)));
+        decode(&queue, 1, 100);
+        let index = queue.take_newest(|_| true).unwrap();
+        // 描画スレッドが持っている間にスロットが一周しても書き換わらない
+        for value in 2..10 {
+            decode(&queue, value, value as i64 * 100);
+        }
+        assert_eq!(queue.frame(index).planes[0][0], 1);
+        queue.release(index);
+        let index = queue.take_newest(|_| true).unwrap();
+        assert_eq!(queue.frame(index).planes[0][0], 9);
+    }
+
+    #[test]
+    fn accepts_display_before_unlock() {
+        let queue = FrameQueue::new();
+        queue.set_format(Some(format(4, 2)));
+        for value in 1..10 {
+            let id = write(&queue, value);
+            queue.display(id, value as i64 * 100);
+            // unlock されるまでは渡さない
+            assert!(!queue.pending());
+            queue.unlock(id);
+            let index = queue.take_newest(|_| true).unwrap();
+            assert_eq!(queue.frame(index).planes[0][0], value);
+            queue.release(index);
+        }
+    }
+
+    #[test]
+    fn reuses_frames_never_displayed() {
+        let queue = FrameQueue::new();
+        queue.set_format(Some(format(4, 2)));
+        // スロットの数より多く捨てられても書き込み先がなくならない
+        for value in 0..SLOTS as u8 * 2 {
+            let id = write(&queue, value);
+            assert_ne!(id, NO_SLOT);
+            queue.unlock(id);
+        }
+        assert_eq!(queue.take_newest(|_| true), None);
+        decode(&queue, 42, 100);
+        let index = queue.take_newest(|_| true).unwrap();
+        assert_eq!(queue.frame(index).planes[0][0], 42);
+    }
+
+    #[test]
+    fn ignores_callbacks_for_reused_slot() {
+        let queue = FrameQueue::new();
+        queue.set_format(Some(format(4, 2)));
+        let stale = write(&queue, 1);
+        queue.unlock(stale);
+        // 捨てられたスロットが使い直されたあとに前の display が来ても渡さない
+        let ids: Vec<usize> = (2..=SLOTS as u8 + 1).map(|value| write(&queue, value)).collect();
+        queue.display(stale, 100);
+        assert!(!queue.pending());
+        for id in ids {
+            queue.unlock(id);
+            queue.display(id, 200);
+        }
+        let index = queue.take_newest(|_| true).unwrap();
+        assert_eq!(queue.frame(index).planes[0][0], SLOTS as u8 + 1);
+    }
+}
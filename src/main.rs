@@ -1,215 +1,441 @@
+mod audio;
+mod channels;
+mod clock;
+mod config;
+mod device;
+mod dsp;
+mod effects;
+mod frames;
 mod media;
+mod options;
+mod resampler;
+mod silence;
+mod sfx;
+mod sink;
+mod spatial;
 mod support;
+mod visualizer;
 
 extern crate vlc;
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use vlc::Event as VlcEvent;
-use vlc::{EventType, Instance, Media, MediaPlayer, State};
+use vlc::{EventType, Instance, Media, MediaPlayer, MediaPlayerAudioEx};
 
-use glutin::event::{Event, WindowEvent, ElementState, VirtualKeyCode};
-use glutin::event_loop::{ControlFlow, EventLoop};
+use glutin::event::{Event, WindowEvent, ElementState, MouseScrollDelta, StartCause, VirtualKeyCode};
+use glutin::event_loop::{ControlFlow, EventLoop, EventLoopProxy};
 use glutin::window::WindowBuilder;
-use glutin::ContextBuilder;
+use glutin::{ContextBuilder, GlProfile, GlRequest};
 
-use libc::c_void;
-use media::{MediaExt, MediaPlayerExt};
+use media::{Equalizer, MediaExt, MediaPlayerExt};
+use support::{ColorMatrix, ColorSpace, PixelFormat};
+use frames::{FrameFormat, FrameQueue};
 use std::sync::{Arc, Mutex};
 
-use alto::{Alto, Source, Stereo, SourceState};
+use channels::ChannelLayout;
+use config::{Config, MAX_SOUND_VOLUME, MAX_VOLUME};
+use spatial::QuadMotion;
+use options::{EndAction, Options};
+use sfx::SoundBank;
+use sink::AudioSink;
+use silence::{SilenceDetector, SilenceEvent};
+use visualizer::{Analyzer, SampleRing, Visualization};
 
 const TARGET_FPS: u64 = 60;
+// 音量の刻み (%)
+const VOLUME_STEP: i32 = 5;
+// 効果音の音量の刻み (%)
+const SOUND_VOLUME_STEP: i32 = 10;
+// プリアンプの刻みと範囲 (dB)
+const PREAMP_STEP: f32 = 1.0;
+const MAX_PREAMP: f32 = 20.0;
+// 音声の遅れの刻みと範囲 (ms)
+const DELAY_STEP: i64 = 10;
+const MAX_DELAY: i64 = 5000;
+// 無音の間の再生速度
+const SILENCE_RATE: f32 = 3.0;
+
+// libvlc のスレッドからイベントループに送るイベント
+#[derive(Debug)]
+enum PlayerEvent {
+    // 音声を最後まで鳴らし終えた、または最後まで再生した
+    EndOfMedia,
+    Error,
+    Silence(SilenceEvent),
+    // VLC が新しいフレームを display した
+    Frame,
+    // 再生を始めた (一時停止から戻ったときも)
+    Playing,
+}
+
+// 終了のイベントは drain と EndReached の両方から来るので 1 回だけ送る
+fn notify_end(ended: &AtomicBool, proxy: &EventLoopProxy<PlayerEvent>, event: PlayerEvent) {
+    if !ended.swap(true, Ordering::SeqCst) {
+        proxy.send_event(event).ok();
+    }
+}
+
+// 止めてから再生し直す
+// stop が戻ったあとは前の再生のイベントは来ないので、そこで終了のフラグを戻す
+fn restart(mdp: &MediaPlayer, ended: &AtomicBool, delay: i64) {
+    mdp.stop();
+    ended.store(false, Ordering::SeqCst);
+    mdp.play().unwrap();
+    apply_audio_delay(mdp, delay);
+}
+
+// libvlc が play に渡す pts をずらすので、映像を出す時刻を決める音声の時計もその分ずれる
+fn apply_audio_delay(mdp: &MediaPlayer, delay: i64) {
+    if let Err(err) = mdp.set_audio_delay(delay * 1000) {
+        println!("{}", err);
+    }
+}
+
+fn change_audio_delay(mdp: &MediaPlayer, config: &mut Config, path: &str, delta: i64) {
+    let delay = (config.audio_delay(path) + delta).clamp(-MAX_DELAY, MAX_DELAY);
+    config.set_audio_delay(path, delay);
+    apply_audio_delay(mdp, delay);
+    println!("audio delay: {:+}ms", delay);
+    config.save();
+}
+
+// saved は無音を飛ばして短くなった時間 (秒)、飛ばしていなければ None
+fn window_title(path: &str, delay: i64, saved: Option<f64>) -> String {
+    let mut title = format!("{} - audio delay {:+}ms", path, delay);
+    if let Some(saved) = saved {
+        title += &format!(" - skip silence, saved {:.1}s", saved);
+    }
+    title
+}
+
+// 無音の間は速く再生する
+fn set_silence_rate(mdp: &MediaPlayer, fast: bool) {
+    let rate = if fast { SILENCE_RATE } else { 1.0 };
+    if mdp.set_rate(rate).is_err() {
+        println!("Failed to set rate {}", rate);
+    }
+}
+
+// 音量を変えて保存する
+// 実際の音量は libvlc から set_volume のコールバックで届く
+fn change_volume(mdp: &MediaPlayer, config: &mut Config, delta: i32) {
+    config.volume = (config.volume + delta).clamp(0, MAX_VOLUME);
+    mdp.set_volume(config.volume).ok();
+    println!("volume: {}%", config.volume);
+    config.save();
+}
+
+fn toggle_mute(mdp: &MediaPlayer, config: &mut Config) {
+    config.muted = !config.muted;
+    mdp.set_mute(config.muted);
+    println!("mute: {}", config.muted);
+    config.save();
+}
+
+// libvlc の音量と同じく 3 乗で聞こえ方に合わせる
+fn sound_gain(volume: i32) -> f32 {
+    (volume as f32 / 100.0).powi(3)
+}
+
+fn change_sound_volume(audio: &dyn AudioSink, config: &mut Config, delta: i32) {
+    config.sound_volume = (config.sound_volume + delta).clamp(0, MAX_SOUND_VOLUME);
+    audio.set_sound_volume(sound_gain(config.sound_volume));
+    println!("sound volume: {}%", config.sound_volume);
+    config.save();
+}
+
+// config.equalizer の名前のプリセットの番号
+fn equalizer_preset(config: &Config) -> Option<usize> {
+    let name = config.equalizer.as_ref()?;
+    Equalizer::presets().iter().position(|preset| preset == name)
+}
+
+// config のイコライザーを libvlc に設定する
+// プリセットもプリアンプも指定がなければイコライザーを外す
+fn apply_equalizer(mdp: &MediaPlayer, config: &Config) {
+    let preset = equalizer_preset(config);
+    let equalizer = match (preset, config.preamp) {
+        (None, None) => {
+            mdp.set_equalizer(None).ok();
+            println!("equalizer: off");
+            return;
+        }
+        (Some(index), _) => Equalizer::from_preset(index as u32),
+        (None, Some(_)) => Equalizer::new(),
+    };
+    let mut equalizer = match equalizer {
+        Some(equalizer) => equalizer,
+        None => {
+            println!("Failed to create equalizer");
+            return;
+        }
+    };
+    if let Some(preamp) = config.preamp {
+        if let Err(err) = equalizer.set_preamp(preamp) {
+            println!("{}", err);
+        }
+    }
+
+    let bands: Vec<String> = Equalizer::bands()
+        .iter()
+        .enumerate()
+        .map(|(i, frequency)| format!("{}Hz {:+.1}", frequency, equalizer.amp(i as u32)))
+        .collect();
+    println!(
+        "equalizer: {} preamp {:+.1}dB [{}]",
+        config.equalizer.as_deref().unwrap_or("flat"),
+        equalizer.preamp(),
+        bands.join(", ")
+    );
+    if let Err(err) = mdp.set_equalizer(Some(&equalizer)) {
+        println!("{}", err);
+    }
+}
+
+// なし → 1 番目のプリセット → ... → 最後 → なし
+fn next_equalizer(mdp: &MediaPlayer, config: &mut Config) {
+    let next = equalizer_preset(config).map(|index| index + 1).unwrap_or(0);
+    config.equalizer = Equalizer::presets().get(next).cloned();
+    config.preamp = None;
+    apply_equalizer(mdp, config);
+    config.save();
+}
+
+fn change_preamp(mdp: &MediaPlayer, config: &mut Config, delta: f32) {
+    // 変えていなければプリセットのプリアンプから
+    let preamp = config.preamp.unwrap_or_else(|| {
+        equalizer_preset(config)
+            .and_then(|index| Equalizer::from_preset(index as u32))
+            .map(|equalizer| equalizer.preamp())
+            .unwrap_or(0.0)
+    });
+    config.preamp = Some((preamp + delta).clamp(-MAX_PREAMP, MAX_PREAMP));
+    apply_equalizer(mdp, config);
+    config.save();
+}
+
+// パースしてプレイリスト (YouTube など) なら最初の項目を返す
+fn load_media(instance: &Instance, path: &str) -> Result<Media, String> {
+    let md = Media::new_location(instance, path).ok_or("Failed to create media")?;
+
+    let (tx, rx) = channel::<()>();
+    let em = md.event_manager();
+    let _ = em.attach(EventType::MediaParsedChanged, move |e, _| match e {
+        VlcEvent::MediaParsedChanged(s) => {
+            match s as u32 {
+                media::MediaParsedStatusDone => {
+                    // Media parsed
+                    tx.send(()).unwrap();
+                }
+                _ => {
+                    println!("Media not parsed");
+                }
+            }
+        }
+        _ => (),
+    });
+
+    md.parse_with_options(media::MediaParseNetwork, -1)?;
+    rx.recv().unwrap();
+    if let Some(submd) = md.subitems().item_at_index(0) {
+        Ok(submd)
+    } else {
+        Ok(md)
+    }
+}
 
 fn main() -> Result<(), String> {
-    let sample_channel = 2;
-    let sample_freq: u32 = 44100;
-
-    let alto = Alto::load_default().unwrap();
-    let al_device = alto.open(None).unwrap(); // Opens the default audio device
-    let al_context = al_device.new_context(None).unwrap(); // Creates a default context
-    
-    // Configure listener
-    al_context.set_position([1.0, 4.0, 5.0]).unwrap();
-    al_context.set_velocity([2.5, 0.0, 0.0]).unwrap();
-    al_context.set_orientation(([0.0, 0.0, 1.0], [0.0, 1.0, 0.0])).unwrap();
-    
-    let source = al_context.new_streaming_source().unwrap();
-    let source = Arc::new(Mutex::new(source));
+    let args: Vec<String> = std::env::args().collect();
+    let options = Options::parse(&args)?;
+
+    if options.list_devices {
+        let alto = alto::Alto::load_default().map_err(|err| format!("Failed to load OpenAL: {}", err))?;
+        for name in device::output_names(&alto) {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+    let audio = sink::open(&options)?;
+    let mut config = Config::load();
+    if !options.sounds.is_empty() {
+        audio.set_sounds(Arc::new(SoundBank::load(&options.sounds)?));
+    }
+    audio.set_sound_volume(sound_gain(config.sound_volume));
 
     // TODO: Linux, Mac対応
     // OK: Audio OpenAL
     // OK: YouTube対応
     // OK: 一時停止したときにポーズされるようにする
-    let args: Vec<String> = std::env::args().collect();
-
-    let path = match args.get(1) {
-        Some(s) => s,
-        None => {
-            return Err("No media file specified".to_string());
-        }
-    };
     let instance = Instance::new().ok_or("Failed to create instance")?;
-
-    let md = Media::new_location(&instance, path).ok_or("Failed to create media")?;
     let mdp = MediaPlayer::new(&instance).ok_or("Failed to create media player")?;
 
-    struct VlcContext {
-        pixel_buffer: Vec<u32>,
-        need_update: bool,
-        locked: bool,
-    }
-    
-    let (video_width, video_height) = (512, 512);
-    mdp.set_video_format(
-        "RV24",
-        video_width,
-        video_height,
-        video_width * 3,
-    );
-    let context = Arc::new(Mutex::new(VlcContext {
-        pixel_buffer: vec![0; video_width as usize * video_height as usize],
-        need_update: false,
-        locked: false,
-    }));
-    let c1 = Arc::clone(&context);
-    let c2 = Arc::clone(&context);
+    let el = EventLoop::<PlayerEvent>::with_user_event();
+    let proxy = el.create_proxy();
+    let ended = Arc::new(AtomicBool::new(false));
+
+    let frames = Arc::new(FrameQueue::new());
+    let f1 = Arc::clone(&frames);
+    let f2 = Arc::clone(&frames);
+    let f3 = Arc::clone(&frames);
+    let f4 = Arc::clone(&frames);
+    let f5 = Arc::clone(&frames);
+    let frame_proxy = proxy.clone();
     mdp.set_video_callbacks(
-        move || {
-            let mut context = c1.lock().unwrap();
-            context.locked = true;
-            context.pixel_buffer.as_mut_ptr() as *mut c_void
+        move |planes| {
+            let (id, buffers) = f1.lock();
+            *planes = buffers;
+            id
         },
+        Some(Box::new(move |id| f2.unlock(id))),
+        Some(Box::new(move |id| {
+            // VLC は表示すべき時刻に display を呼ぶので、その時刻をフレームの pts とする
+            f4.display(id, media::clock());
+            // 新しいフレームが来たときだけ描き直す
+            frame_proxy.send_event(PlayerEvent::Frame).ok();
+        })),
+        Some(Box::new(move |format| {
+            // 元の解像度のまま受け取り、YUV はシェーダーで RGB に変換する
+            println!("video format: {} {}x{}", format.chroma_str(), format.width, format.height);
+            let pixel_format = match PixelFormat::from_chroma(&format.chroma_str()) {
+                Some(PixelFormat::Rgb24) => PixelFormat::Rgb24,
+                Some(PixelFormat::Nv12) => PixelFormat::Nv12,
+                _ => PixelFormat::I420,
+            };
+            let source = format.chroma_str();
+            let output = pixel_format.output_chroma(&source);
+            let color_space = ColorSpace::detect(&source, output, format.height);
+            format.set_chroma(output);
+
+            let planes = pixel_format.planes(format.width, format.height);
+            for i in 0..3 {
+                let (plane_width, plane_height, bpp) = planes.get(i).copied().unwrap_or((0, 0, 0));
+                format.pitches[i] = plane_width * bpp;
+                format.lines[i] = plane_height;
+            }
+            // バッファは lock でスロットごとに確保し直す
+            f3.set_format(Some(FrameFormat {
+                pixel_format,
+                width: format.width,
+                height: format.height,
+                color_space,
+            }));
+            1
+        })),
         Some(Box::new(move || {
-            let mut context = c2.lock().unwrap();
-            context.locked = false;
+            // 次のファイルに映像がなければ format は呼ばれない
+            f5.set_format(None);
         })),
-        Some(Box::new(|| {})),
     );
 
-    mdp.set_audio_format("S16N", sample_freq, sample_channel);
-    let s1 = Arc::clone(&source);
-    let s2 = Arc::clone(&source);
-    let s3 = Arc::clone(&source);
-    let s4 = Arc::clone(&source);
-    let flush: Arc<Mutex<Option<i64>>> = Arc::new(Mutex::new(None));
-    let r1 = Arc::clone(&flush);
-    let r2 = Arc::clone(&flush);
-    let r3 = Arc::clone(&flush);
-    let r4 = Arc::clone(&flush);
-    mdp.set_callbacks(
-        move |samples, mut count, pts| {
+    let a1 = Arc::clone(&audio);
+    let a2 = Arc::clone(&audio);
+    let a3 = Arc::clone(&audio);
+    let a4 = Arc::clone(&audio);
+    let a5 = Arc::clone(&audio);
+    let a6 = Arc::clone(&audio);
+    let a7 = Arc::clone(&audio);
+    // 映像がないときに表示するビジュアライザー用
+    let tap = Arc::new(SampleRing::new());
+    let t1 = Arc::clone(&tap);
+    let t2 = Arc::clone(&tap);
+    let silence = Arc::new(Mutex::new(SilenceDetector::new(options.silence, 48000)));
+    let s1 = Arc::clone(&silence);
+    let s2 = Arc::clone(&silence);
+    let silence_proxy = proxy.clone();
+    let drain_proxy = proxy.clone();
+    let drain_ended = Arc::clone(&ended);
+    mdp.set_audio_callbacks(
+        move |samples, count, pts| {
             println!("play\t{}\t{}", count, pts);
-            let mut source = s1.lock().unwrap();
-            let mut samples = samples as *const i16;
-            // let mut flush = r1.lock().unwrap();
-            // if let Some(flushed) = *flush {
-            //     for _i in 0..source.buffers_processed() {
-            //         source.unqueue_buffer().unwrap();
-            //     }
-            //     let offset = std::cmp::min((pts - flushed) * sample_freq as i64 / 1_000_000, count as i64);
-            //     samples = unsafe { samples.offset(offset as isize * std::mem::size_of::<i16>() as isize) };
-            //     count -= offset as u32;
-            //     *flush = None;
-            // };
-
-            let sample_vec = unsafe {
-                std::slice::from_raw_parts(samples, count as usize * std::mem::size_of::<i16>())
+            let channels = a1.channels();
+            let samples = unsafe {
+                std::slice::from_raw_parts(samples as *const f32, count as usize * channels as usize)
             };
-            let buf = if source.buffers_processed() <= 0 {
-                al_context.new_buffer::<Stereo<i16>, _>(sample_vec, sample_freq as i32).unwrap()
-            } else {
-                let mut buf = source.unqueue_buffer().unwrap();
-                buf.set_data::<Stereo<i16>, _>(sample_vec, sample_freq as i32).unwrap();
-                buf
-            };
-            source.queue_buffer(buf).unwrap();
-            let state = source.state();
-            if state != SourceState::Playing {
-                source.play();
-            }
+            t1.push(samples, channels as usize, pts);
+            s1.lock().unwrap().process(samples, channels as usize, |event| {
+                silence_proxy.send_event(PlayerEvent::Silence(event)).ok();
+            });
+            a1.play(samples, pts);
         },
         Some(Box::new(move |pts| {
             println!("pause: {}", pts);
-            let mut source = s2.lock().unwrap();
-            source.stop();
-            for _i in 0..source.buffers_processed() {
-                source.unqueue_buffer().unwrap();
-            }
-            source.play();
-            *r2.lock().unwrap() = Some(pts);
+            a2.pause(pts);
         })),
         Some(Box::new(move |pts| {
             println!("resume: {}", pts);
-            let mut source = s3.lock().unwrap();
-            source.stop();
-            for _i in 0..source.buffers_processed() {
-                source.unqueue_buffer().unwrap();
-            }
-            source.play();
-            *r3.lock().unwrap() = Some(pts);
+            a3.resume(pts);
         })),
         Some(Box::new(move |pts| {
             println!("flush: {}", pts);
-            let mut source = s4.lock().unwrap();
-            source.stop();
-            for _i in 0..source.buffers_processed() {
-                source.unqueue_buffer().unwrap();
-            }
-            source.play();
-            *r4.lock().unwrap() = Some(pts);
+            a4.flush(pts);
         })),
         Some(Box::new(move || {
+            // 鳴らし終えるまで戻らないので、libvlc の EndReached もそのあとになる
             println!("drain");
+            a6.drain();
+            notify_end(&drain_ended, &drain_proxy, PlayerEvent::EndOfMedia);
+        })),
+        Some(Box::new(move |volume, mute| {
+            println!("set volume: {} {}", volume, mute);
+            a7.set_volume(volume, mute);
         })),
+        Some(Box::new(move |format| {
+            // チャンネル配置と周波数は元のまま、サンプルは float で受け取る
+            // 周波数がデバイスと違えば OpenALQueue で変換する
+            println!("audio format: {} {}Hz {}ch", format.format_str(), format.rate, format.channels);
+            let layout = ChannelLayout::from_channels(format.channels);
+            format.set_format("FL32");
+            format.channels = layout.channels();
+            t2.set_rate(format.rate);
+            s2.lock().unwrap().configure(format.rate);
+            a5.configure(format.rate, layout);
+            true
+        })),
+        None,
     );
 
-    let (tx, rx) = channel::<()>();
-    let em = md.event_manager();
-    let _ = em.attach(EventType::MediaParsedChanged, move |e, _| match e {
-        VlcEvent::MediaParsedChanged(s) => {
-            match s as u32 {
-                media::MediaParsedStatusDone => {
-                    // Media parsed
-                    tx.send(()).unwrap();
-                }
-                _ => {
-                    println!("Media not parsed");
-                }
-            }
-        }
-        _ => (),
-    });
-
-    let md = {
-        md.parse_with_options(media::MediaParseNetwork, -1)?;
-        rx.recv().unwrap();
-        if let Some(submd) = md.subitems().item_at_index(0) {
-            submd
-        } else {
-            md
-        }
-    };
+    {
+        // 音声のない動画では drain が呼ばれないので EndReached でも終了を知らせる
+        let em = mdp.event_manager();
+        let end_proxy = proxy.clone();
+        let end_ended = Arc::clone(&ended);
+        let _ = em.attach(EventType::MediaPlayerEndReached, move |_, _| {
+            notify_end(&end_ended, &end_proxy, PlayerEvent::EndOfMedia);
+        });
+        // 映像がなければフレームが来ないので、ビジュアライザーを動かし始めるきっかけにする
+        let playing_proxy = proxy.clone();
+        let _ = em.attach(EventType::MediaPlayerPlaying, move |_, _| {
+            playing_proxy.send_event(PlayerEvent::Playing).ok();
+        });
+        let error_proxy = proxy.clone();
+        let error_ended = Arc::clone(&ended);
+        let _ = em.attach(EventType::MediaPlayerEncounteredError, move |_, _| {
+            notify_end(&error_ended, &error_proxy, PlayerEvent::Error);
+        });
+    }
 
-    let em = md.event_manager();
-    let _ = em.attach(EventType::MediaStateChanged, move |e, _| match e {
-        VlcEvent::MediaStateChanged(s) => {
-            //println!("State : {:?}", s);
-            if s == State::Ended || s == State::Error {
-                // Ended
-            }
-        }
-        _ => (),
-    });
+    // 前回の音量とイコライザー
+    mdp.set_volume(config.volume).ok();
+    mdp.set_mute(config.muted);
+    apply_equalizer(&mdp, &config);
 
+    let md = load_media(&instance, &options.paths[0])?;
     mdp.set_media(&md);
     // Start playing
     mdp.play().map_err(|_| "Failed to play")?;
+    let delay = config.audio_delay(&options.paths[0]);
+    apply_audio_delay(&mdp, delay);
 
-    let el = EventLoop::new();
-    let wb = WindowBuilder::new().with_title("A fantastic window!");
+    let title = window_title(&options.paths[0], delay, options.silence.enabled.then_some(0.0));
+    let wb = WindowBuilder::new().with_title(&title);
 
+    // 互換プロファイルのないドライバーでも動くようにコアプロファイルを使う
     let windowed_context = ContextBuilder::new()
+        .with_gl(GlRequest::Specific(glutin::Api::OpenGl, (3, 3)))
+        .with_gl_profile(GlProfile::Core)
+        .with_vsync(options.vsync)
         .build_windowed(wb, &el)
         .map_err(|err| err.to_string())?;
     let windowed_context = unsafe { windowed_context.make_current().unwrap() };
@@ -221,21 +447,88 @@ fn main() -> Result<(), String> {
 
     struct GameState {
         pos: [f64; 2],
+        // options.paths の何番目を再生しているか
+        index: usize,
+        color_space: ColorSpace,
+        // タグが間違っているファイル用に変換行列を上書きする
+        color_matrix: Option<ColorMatrix>,
+        // 音を映像の位置から鳴らす
+        spatialize: bool,
+        doppler: bool,
+        motion: QuadMotion,
+        hrtf: bool,
+        reverb: Option<usize>,
+        // None のときも映像がなければスペクトラムを出す
+        visualization: Option<Visualization>,
+        // 映像に重ねずに画面全体に出す
+        visualizer_full: bool,
+        analyzer: Analyzer,
+        // PBO から転送して GPU が読み終えるのを待っているスロット
+        uploading: Vec<usize>,
+        skip_silence: bool,
+        // いま無音の区間にいる
+        silent: bool,
+        last_redraw: Instant,
+        title: String,
+        // 最小化している間は描かない
+        minimized: bool,
+        // 新しいフレームがなくても TARGET_FPS で描き直す
+        animating: bool,
     }
 
-    let gl = support::load(&windowed_context.context());
-    let mut state = GameState { pos: [0.0, 0.0] };
+    let gl = support::load(&windowed_context.context()).map_err(|err| err.to_string())?;
+    let mut state = GameState {
+        pos: [0.0, 0.0],
+        index: 0,
+        color_space: ColorSpace::detect("I420", "I420", 0),
+        color_matrix: options.color_matrix,
+        // モノラルにダウンミックスされるので P キーで切り替えたときだけ
+        spatialize: false,
+        doppler: false,
+        motion: QuadMotion::default(),
+        hrtf: options.effects.hrtf,
+        reverb: options.effects.reverb,
+        visualization: None,
+        visualizer_full: false,
+        analyzer: Analyzer::new(),
+        uploading: Vec::new(),
+        skip_silence: options.silence.enabled,
+        silent: false,
+        last_redraw: Instant::now(),
+        title,
+        minimized: false,
+        animating: false,
+    };
+    audio.set_spatialize(state.spatialize);
+    audio.set_doppler(state.doppler);
 
     el.run(move |event, _, control_flow| {
         //println!("{:?}", event);
-        let start_time = Instant::now();
+        // 新しいフレーム、再生の開始、操作や大きさの変更、無音の区間の切り替わりがあれば描き直す
+        let redraw = matches!(
+            event,
+            Event::WindowEvent { .. } | Event::UserEvent(PlayerEvent::Frame | PlayerEvent::Playing | PlayerEvent::Silence(_))
+        );
 
         match event {
             Event::LoopDestroyed => return,
+            Event::NewEvents(StartCause::ResumeTimeReached { .. }) => windowed_context.window().request_redraw(),
             Event::WindowEvent { event, .. } => match event {
-                WindowEvent::Resized(physical_size) => windowed_context.resize(physical_size),
+                WindowEvent::Resized(physical_size) => {
+                    windowed_context.resize(physical_size);
+                    state.minimized = physical_size.width == 0 || physical_size.height == 0;
+                }
                 WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
                 WindowEvent::CursorMoved { position, .. } => state.pos = [position.x, position.y],
+                WindowEvent::MouseWheel { delta, .. } => {
+                    let y = match delta {
+                        MouseScrollDelta::LineDelta(_, y) => y as f64,
+                        MouseScrollDelta::PixelDelta(position) => position.y,
+                    };
+                    if y != 0.0 {
+                        change_volume(&mdp, &mut config, VOLUME_STEP * y.signum() as i32);
+                    }
+                }
                 WindowEvent::KeyboardInput { device_id: _, input, is_synthetic } => {
                     if is_synthetic {
                         return;
@@ -245,10 +538,87 @@ fn main() -> Result<(), String> {
                             match key {
                                 VirtualKeyCode::Escape => *control_flow = ControlFlow::Exit,
                                 VirtualKeyCode::Space => mdp.set_pause(mdp.is_playing()),
-                                VirtualKeyCode::Z => { mdp.stop(); mdp.play().unwrap(); },
+                                VirtualKeyCode::Z => restart(&mdp, &ended, config.audio_delay(&options.paths[state.index])),
                                 VirtualKeyCode::Return => mdp.set_position(0.0),
                                 VirtualKeyCode::Right => mdp.set_position(mdp.get_position().unwrap() + 1.0),
                                 VirtualKeyCode::Left => mdp.set_position(mdp.get_position().unwrap() - 1.0),
+                                VirtualKeyCode::C => {
+                                    // 自動 → BT.601 → BT.709 → BT.2020 → 自動
+                                    state.color_matrix = match state.color_matrix {
+                                        None => Some(ColorMatrix::Bt601),
+                                        Some(ColorMatrix::Bt2020) => None,
+                                        Some(matrix) => Some(matrix.next()),
+                                    };
+                                    println!("color matrix override: {:?}", state.color_matrix);
+                                }
+                                VirtualKeyCode::Up => change_volume(&mdp, &mut config, VOLUME_STEP),
+                                VirtualKeyCode::Down => change_volume(&mdp, &mut config, -VOLUME_STEP),
+                                VirtualKeyCode::M => toggle_mute(&mdp, &mut config),
+                                VirtualKeyCode::E => next_equalizer(&mdp, &mut config),
+                                VirtualKeyCode::PageUp => change_preamp(&mdp, &mut config, PREAMP_STEP),
+                                VirtualKeyCode::PageDown => change_preamp(&mdp, &mut config, -PREAMP_STEP),
+                                VirtualKeyCode::P => {
+                                    state.spatialize = !state.spatialize;
+                                    println!("spatialize: {}", state.spatialize);
+                                    audio.set_spatialize(state.spatialize);
+                                }
+                                VirtualKeyCode::O => {
+                                    state.doppler = !state.doppler;
+                                    println!("doppler: {}", state.doppler);
+                                    audio.set_doppler(state.doppler);
+                                }
+                                VirtualKeyCode::H => {
+                                    state.hrtf = !state.hrtf;
+                                    audio.set_hrtf(state.hrtf);
+                                }
+                                VirtualKeyCode::R => {
+                                    state.reverb = effects::next_reverb(state.reverb);
+                                    println!("reverb preset: {}", effects::reverb_name(state.reverb));
+                                    audio.set_reverb(state.reverb);
+                                }
+                                VirtualKeyCode::J | VirtualKeyCode::K => {
+                                    // J で音声を早く、K で遅く
+                                    let step = if key == VirtualKeyCode::J { -DELAY_STEP } else { DELAY_STEP };
+                                    change_audio_delay(&mdp, &mut config, &options.paths[state.index], step);
+                                }
+                                VirtualKeyCode::S => {
+                                    state.skip_silence = !state.skip_silence;
+                                    println!("skip silence: {}", state.skip_silence);
+                                    silence.lock().unwrap().set_enabled(state.skip_silence);
+                                    if state.silent {
+                                        set_silence_rate(&mdp, state.skip_silence);
+                                    }
+                                }
+                                VirtualKeyCode::V => {
+                                    state.visualization = Visualization::next(state.visualization);
+                                    println!("visualization: {:?}", state.visualization);
+                                }
+                                VirtualKeyCode::B => {
+                                    state.visualizer_full = !state.visualizer_full;
+                                    println!("visualizer full view: {}", state.visualizer_full);
+                                }
+                                VirtualKeyCode::Key1
+                                | VirtualKeyCode::Key2
+                                | VirtualKeyCode::Key3
+                                | VirtualKeyCode::Key4
+                                | VirtualKeyCode::Key5
+                                | VirtualKeyCode::Key6
+                                | VirtualKeyCode::Key7
+                                | VirtualKeyCode::Key8
+                                | VirtualKeyCode::Key9 => {
+                                    let index = key as usize - VirtualKeyCode::Key1 as usize;
+                                    if let Err(err) = audio.play_sound(index) {
+                                        println!("{}", err);
+                                    }
+                                }
+                                VirtualKeyCode::Minus => change_sound_volume(&*audio, &mut config, -SOUND_VOLUME_STEP),
+                                VirtualKeyCode::Equals => change_sound_volume(&*audio, &mut config, SOUND_VOLUME_STEP),
+                                VirtualKeyCode::D => {
+                                    // 再生を止めずに次の出力デバイスへ
+                                    if let Err(err) = audio.next_device() {
+                                        println!("{}", err);
+                                    }
+                                }
                                 _ => (),
                             }
                         }
@@ -256,48 +626,135 @@ fn main() -> Result<(), String> {
                 }
                 _ => (),
             },
+            Event::UserEvent(PlayerEvent::Frame | PlayerEvent::Playing) => (),
+            Event::UserEvent(PlayerEvent::Silence(event)) => {
+                // 無音の先は分からないのでシークはせず、速く再生するだけ
+                state.silent = event == SilenceEvent::Start;
+                if state.skip_silence {
+                    set_silence_rate(&mdp, state.silent);
+                }
+            }
+            Event::UserEvent(event) => {
+                println!("{:?}", event);
+                let action = match (event, options.end) {
+                    // エラーのたびに同じものを開き直さない
+                    (PlayerEvent::Error, EndAction::Loop) => EndAction::Hold,
+                    (_, action) => action,
+                };
+                match action {
+                    EndAction::Exit => *control_flow = ControlFlow::Exit,
+                    EndAction::Loop => restart(&mdp, &ended, config.audio_delay(&options.paths[state.index])),
+                    EndAction::Next if state.index + 1 < options.paths.len() => {
+                        state.index += 1;
+                        match load_media(&instance, &options.paths[state.index]) {
+                            Ok(md) => {
+                                mdp.stop();
+                                ended.store(false, Ordering::SeqCst);
+                                mdp.set_media(&md);
+                                mdp.play().unwrap();
+                                apply_audio_delay(&mdp, config.audio_delay(&options.paths[state.index]));
+                            }
+                            Err(err) => {
+                                // 開けなければその次へ進む
+                                println!("{}", err);
+                                proxy.send_event(PlayerEvent::Error).ok();
+                            }
+                        }
+                    }
+                    EndAction::Next | EndAction::Hold => (),
+                }
+            }
             Event::RedrawRequested(_) => {
-                match context.try_lock() {
-                    Ok(mut mutex) => {
-                        let mut context = &mut *mutex;
-                        if !context.locked {
-                            unsafe {
-                                gl.upload_texture(
-                                    context.pixel_buffer.as_ptr() as *const _,
-                                    video_width,
-                                    video_height,
-                                );
+                let audio_time = audio.time();
+                let format = frames.format();
+                // VLC が書き込んでいないスロットの PBO をフォーマットに合わせて作り直す
+                if let Some(format) = format.filter(|_| gl.buffer_storage) {
+                    for i in 0..frames::SLOTS {
+                        frames.with_free(i, |frame| {
+                            if frame.mapped_format != Some(format) {
+                                frame.mapped = gl.create_pbo(i, format.pixel_format, format.width, format.height);
+                                frame.mapped_format = Some(format);
                             }
-                            context.need_update = false;
+                        });
+                    }
+                }
+                // GPU が PBO を読み終えたスロットを VLC に返す
+                state.uploading.retain(|&i| {
+                    let idle = gl.pbo_idle(i);
+                    if idle {
+                        frames.release(i);
+                    }
+                    !idle
+                });
+                if let Some(i) = frames.take_newest(|pts| clock::frame_due(audio_time, pts)) {
+                    let frame = frames.frame(i);
+                    if let Some(format) = frame.format {
+                        state.color_space = format.color_space;
+                    }
+                    // release したあとは frame に触らない
+                    match frame.format {
+                        Some(_) if frame.in_pbo => {
+                            gl.upload_pbo(i);
+                            state.uploading.push(i);
                         }
+                        Some(format) => {
+                            gl.upload_planes(format.pixel_format, &frame.planes, format.width, format.height);
+                            frames.release(i);
+                        }
+                        None => frames.release(i),
                     }
-                    Err(_) => (),
-                };
+                }
+                let has_video = format.is_some_and(|format| format.width > 0 && format.height > 0);
+                let mut color_space = state.color_space;
+                if let Some(matrix) = state.color_matrix {
+                    color_space.matrix = matrix;
+                }
+                gl.set_color_space(color_space);
                 gl.draw_frame([1.0, 0.5, 0.7, 1.0], state.pos);
+                let visualization = match state.visualization {
+                    None if !has_video => Some(Visualization::Spectrum),
+                    visualization => visualization,
+                };
+                if let Some(visualization) = visualization {
+                    let values = match visualization {
+                        Visualization::Spectrum => state.analyzer.spectrum(&tap, audio_time),
+                        Visualization::Oscilloscope => state.analyzer.scope(&tap, audio_time),
+                    };
+                    gl.draw_visualizer(visualization, values, state.visualizer_full || !has_video);
+                }
+                let (position, velocity) = state.motion.update(support::quad_center(state.pos));
+                audio.set_position(position, velocity);
+
+                // 速く鳴らした時間の (速度 - 1) 倍を稼いでいる
+                let saved = silence.lock().unwrap().skipped_secs() * (SILENCE_RATE as f64 - 1.0);
+                state.last_redraw = Instant::now();
+                let path = &options.paths[state.index];
+                let title = window_title(path, config.audio_delay(path), state.skip_silence.then_some(saved));
+                if title != state.title {
+                    windowed_context.window().set_title(&title);
+                    state.title = title;
+                }
                 windowed_context.swap_buffers().unwrap();
+
+                // ビジュアライザー、無音を飛ばして稼いだ時間、ドップラー効果は再生中ずっと動く
+                // 表示する時刻になっていないフレームがあればそれも待つ
+                state.animating = !state.minimized
+                    && (frames.pending()
+                        || (state.doppler && state.motion.moving())
+                        || (mdp.is_playing() && (visualization.is_some() || (state.skip_silence && state.silent))));
             }
             _ => (),
         }
 
+        if redraw && !state.minimized {
+            windowed_context.window().request_redraw();
+        }
         match *control_flow {
             ControlFlow::Exit => (),
+            // 一時停止中や最小化中は次のイベントまで何もしない
+            _ if !state.animating => *control_flow = ControlFlow::Wait,
             _ => {
-                /*
-                 * Grab window handle from the display (untested - based on API)
-                 */
-                windowed_context.window().request_redraw();
-                /*
-                 * Below logic to attempt hitting TARGET_FPS.
-                 * Basically, sleep for the rest of our milliseconds
-                 */
-                let elapsed_time = Instant::now().duration_since(start_time).as_millis() as u64;
-
-                let wait_millis = match 1000 / TARGET_FPS >= elapsed_time {
-                    true => 1000 / TARGET_FPS - elapsed_time,
-                    false => 0,
-                };
-                let new_inst = start_time + std::time::Duration::from_millis(wait_millis);
-                *control_flow = ControlFlow::WaitUntil(new_inst);
+                *control_flow = ControlFlow::WaitUntil(state.last_redraw + Duration::from_millis(1000 / TARGET_FPS));
             }
         }
     });
@@ -1,12 +1,18 @@
-use libc::c_void;
+use libc::{c_char, c_float, c_int, c_uint, c_void};
 use std::mem::transmute;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use vlc::{Media, MediaPlayer};
 use vlc_sys as sys;
 
 pub use sys::libvlc_media_parse_flag_t_libvlc_media_parse_network as MediaParseNetwork;
 pub use sys::libvlc_media_parsed_status_t_libvlc_media_parsed_status_done as MediaParsedStatusDone;
 
+// libvlc_clock() と同じ時計 (マイクロ秒)
+// 音声コールバックの pts もこの時計で渡される
+pub fn clock() -> i64 {
+    unsafe { sys::libvlc_clock() }
+}
+
 pub trait MediaExt {
     fn from_raw(media: *mut sys::libvlc_media_t) -> Self;
 
@@ -51,15 +57,66 @@ impl MediaExt for Media {
     }
 }
 
+// libvlc_video_format_cb に渡されるフォーマット
+// width, height には元の映像サイズが入っているので、必要なら書き換えて返す
+pub struct VideoFormat {
+    pub chroma: [u8; 4],
+    pub width: u32,
+    pub height: u32,
+    pub pitches: [u32; 3],
+    pub lines: [u32; 3],
+}
+
+impl VideoFormat {
+    pub fn chroma_str(&self) -> String {
+        String::from_utf8_lossy(&self.chroma).to_string()
+    }
+
+    pub fn set_chroma(&mut self, chroma: &str) {
+        self.chroma = [0; 4];
+        for (dst, src) in self.chroma.iter_mut().zip(chroma.bytes()) {
+            *dst = src;
+        }
+    }
+}
+
+// libvlc_audio_setup_cb に渡されるフォーマット
+pub struct AudioFormat {
+    pub format: [u8; 4],
+    pub rate: u32,
+    pub channels: u32,
+}
+
+impl AudioFormat {
+    pub fn format_str(&self) -> String {
+        String::from_utf8_lossy(&self.format).to_string()
+    }
+
+    pub fn set_format(&mut self, format: &str) {
+        self.format = [0; 4];
+        for (dst, src) in self.format.iter_mut().zip(format.bytes()) {
+            *dst = src;
+        }
+    }
+}
+
 pub trait MediaPlayerExt {
+    // lock にはプレーンごとのポインタを書き込む配列が渡される
+    // lock が返す値はピクチャーの id として unlock と display に渡される
+    // (unlock と display の順番は VLC のバージョンによって違う)
+    // setup, cleanup を渡すと libvlc_video_set_format_callbacks も設定する
+    // (フォーマットコールバックは lock/unlock/display と同じ opaque を共有するため)
     fn set_video_callbacks<F>(
         &self,
         lock: F,
-        unlock: Option<Box<dyn Fn() + Send + 'static>>,
-        display: Option<Box<dyn Fn() + Send + 'static>>,
+        unlock: Option<Box<dyn Fn(usize) + Send + 'static>>,
+        display: Option<Box<dyn Fn(usize) + Send + 'static>>,
+        setup: Option<Box<dyn Fn(&mut VideoFormat) -> u32 + Send + 'static>>,
+        cleanup: Option<Box<dyn Fn() + Send + 'static>>,
     ) where
-        F: Fn() -> *mut c_void + Send + 'static;
+        F: Fn(&mut [*mut c_void; 3]) -> usize + Send + 'static;
 
+    #[allow(dead_code)]
     fn set_video_format(
         &self,
         chroma: &str,
@@ -68,30 +125,62 @@ pub trait MediaPlayerExt {
         pitch: u32,
     );
 
+    #[allow(dead_code)]
     fn set_audio_format(
         &self,
         format: &str,
         rate: u32,
         channels: u32,
     );
+
+    // vlc::MediaPlayer::set_callbacks と同じだが、set_volume を渡すと
+    // libvlc_audio_set_volume_callback も、setup, cleanup を渡すと
+    // libvlc_audio_set_format_callbacks も設定する (opaque を共有するため)
+    // set_volume は音量 (1.0 が 100%) とミュート
+    // setup が false を返すと再生を中止する
+    #[allow(clippy::too_many_arguments)]
+    fn set_audio_callbacks<F>(
+        &self,
+        play: F,
+        pause: Option<Box<dyn Fn(i64) + Send + 'static>>,
+        resume: Option<Box<dyn Fn(i64) + Send + 'static>>,
+        flush: Option<Box<dyn Fn(i64) + Send + 'static>>,
+        drain: Option<Box<dyn Fn() + Send + 'static>>,
+        set_volume: Option<Box<dyn Fn(f32, bool) + Send + 'static>>,
+        setup: Option<Box<dyn Fn(&mut AudioFormat) -> bool + Send + 'static>>,
+        cleanup: Option<Box<dyn Fn() + Send + 'static>>,
+    ) where
+        F: Fn(*const c_void, u32, i64) + Send + 'static;
+
+    // None ならイコライザーを外す
+    fn set_equalizer(&self, equalizer: Option<&Equalizer>) -> Result<(), String>;
+    // delay はマイクロ秒、正なら音声を遅らせる
+    // メディアが変わると 0 に戻るので再生を始めるたびに設定する
+    fn set_audio_delay(&self, delay: i64) -> Result<(), String>;
 }
 
 impl MediaPlayerExt for MediaPlayer {
     fn set_video_callbacks<F>(
         &self,
         lock: F,
-        unlock: Option<Box<dyn Fn() + Send + 'static>>,
-        display: Option<Box<dyn Fn() + Send + 'static>>,
+        unlock: Option<Box<dyn Fn(usize) + Send + 'static>>,
+        display: Option<Box<dyn Fn(usize) + Send + 'static>>,
+        setup: Option<Box<dyn Fn(&mut VideoFormat) -> u32 + Send + 'static>>,
+        cleanup: Option<Box<dyn Fn() + Send + 'static>>,
     ) where
-        F: Fn() -> *mut c_void + Send + 'static,
+        F: Fn(&mut [*mut c_void; 3]) -> usize + Send + 'static,
     {
         let flag_unlock = unlock.is_some();
         let flag_display = display.is_some();
+        let flag_setup = setup.is_some();
+        let flag_cleanup = cleanup.is_some();
 
         let data = VideoCallbacksData {
             lock: Box::new(lock),
             unlock,
             display,
+            setup,
+            cleanup,
         };
         let data = Box::into_raw(Box::new(data));
 
@@ -111,6 +200,17 @@ impl MediaPlayerExt for MediaPlayer {
                 },
                 data as *mut c_void,
             );
+            if flag_setup {
+                sys::libvlc_video_set_format_callbacks(
+                    self.raw(),
+                    Some(video_cb_setup),
+                    if flag_cleanup {
+                        Some(video_cb_cleanup)
+                    } else {
+                        None
+                    },
+                );
+            }
         }
     }
 
@@ -138,6 +238,102 @@ impl MediaPlayerExt for MediaPlayer {
             sys::libvlc_audio_set_format(self.raw(), c_format.as_ptr(), rate, channels);
         }
     }
+
+    fn set_audio_callbacks<F>(
+        &self,
+        play: F,
+        pause: Option<Box<dyn Fn(i64) + Send + 'static>>,
+        resume: Option<Box<dyn Fn(i64) + Send + 'static>>,
+        flush: Option<Box<dyn Fn(i64) + Send + 'static>>,
+        drain: Option<Box<dyn Fn() + Send + 'static>>,
+        set_volume: Option<Box<dyn Fn(f32, bool) + Send + 'static>>,
+        setup: Option<Box<dyn Fn(&mut AudioFormat) -> bool + Send + 'static>>,
+        cleanup: Option<Box<dyn Fn() + Send + 'static>>,
+    ) where
+        F: Fn(*const c_void, u32, i64) + Send + 'static,
+    {
+        let flag_pause = pause.is_some();
+        let flag_resume = resume.is_some();
+        let flag_flush = flush.is_some();
+        let flag_drain = drain.is_some();
+        let flag_set_volume = set_volume.is_some();
+        let flag_setup = setup.is_some();
+        let flag_cleanup = cleanup.is_some();
+
+        let data = AudioCallbacksData {
+            play: Box::new(play),
+            pause,
+            resume,
+            flush,
+            drain,
+            set_volume,
+            setup,
+            cleanup,
+        };
+        let data = Box::into_raw(Box::new(data));
+
+        unsafe {
+            sys::libvlc_audio_set_callbacks(
+                self.raw(),
+                Some(audio_cb_play),
+                if flag_pause {
+                    Some(audio_cb_pause)
+                } else {
+                    None
+                },
+                if flag_resume {
+                    Some(audio_cb_resume)
+                } else {
+                    None
+                },
+                if flag_flush {
+                    Some(audio_cb_flush)
+                } else {
+                    None
+                },
+                if flag_drain {
+                    Some(audio_cb_drain)
+                } else {
+                    None
+                },
+                data as *mut c_void,
+            );
+            if flag_set_volume {
+                sys::libvlc_audio_set_volume_callback(self.raw(), Some(audio_cb_set_volume));
+            }
+            if flag_setup {
+                sys::libvlc_audio_set_format_callbacks(
+                    self.raw(),
+                    Some(audio_cb_setup),
+                    if flag_cleanup {
+                        Some(audio_cb_cleanup)
+                    } else {
+                        None
+                    },
+                );
+            }
+        }
+    }
+
+    fn set_equalizer(&self, equalizer: Option<&Equalizer>) -> Result<(), String> {
+        let ptr = equalizer.map(|equalizer| equalizer.ptr).unwrap_or(std::ptr::null_mut());
+        // libvlc が設定をコピーするので equalizer はこのあと解放してよい
+        let err = unsafe { sys::libvlc_media_player_set_equalizer(self.raw(), ptr) };
+        if err == 0 {
+            Ok(())
+        } else {
+            Err("Failed to set equalizer".to_string())
+        }
+    }
+
+    fn set_audio_delay(&self, delay: i64) -> Result<(), String> {
+        let err = unsafe { sys::libvlc_audio_set_delay(self.raw(), delay) };
+        if err == 0 {
+            Ok(())
+        } else {
+            Err("Failed to set audio delay".to_string())
+        }
+    }
 }
 
 pub struct MediaList {
@@ -161,29 +357,224 @@ impl Drop for MediaList {
     }
 }
 
+// libvlc_equalizer_t (10 バンド)
+// 増幅量はすべて dB (-20.0 〜 20.0)
+pub struct Equalizer {
+    ptr: *mut sys::libvlc_equalizer_t,
+}
+
+impl Equalizer {
+    // すべてのバンドが 0dB
+    pub fn new() -> Option<Equalizer> {
+        let ptr = unsafe { sys::libvlc_audio_equalizer_new() };
+        if ptr.is_null() {
+            None
+        } else {
+            Some(Equalizer { ptr })
+        }
+    }
+
+    pub fn from_preset(index: u32) -> Option<Equalizer> {
+        let ptr = unsafe { sys::libvlc_audio_equalizer_new_from_preset(index) };
+        if ptr.is_null() {
+            None
+        } else {
+            Some(Equalizer { ptr })
+        }
+    }
+
+    // プリセットの名前 (添字が from_preset に渡す番号)
+    pub fn presets() -> Vec<String> {
+        unsafe {
+            (0..sys::libvlc_audio_equalizer_get_preset_count())
+                .map(|i| {
+                    let name = sys::libvlc_audio_equalizer_get_preset_name(i);
+                    if name.is_null() {
+                        String::new()
+                    } else {
+                        CStr::from_ptr(name).to_string_lossy().into_owned()
+                    }
+                })
+                .collect()
+        }
+    }
+
+    // 各バンドの中心周波数 (Hz)
+    pub fn bands() -> Vec<f32> {
+        unsafe {
+            (0..sys::libvlc_audio_equalizer_get_band_count())
+                .map(|i| sys::libvlc_audio_equalizer_get_band_frequency(i))
+                .collect()
+        }
+    }
+
+    pub fn preamp(&self) -> f32 {
+        unsafe { sys::libvlc_audio_equalizer_get_preamp(self.ptr) }
+    }
+
+    pub fn set_preamp(&mut self, preamp: f32) -> Result<(), String> {
+        let err = unsafe { sys::libvlc_audio_equalizer_set_preamp(self.ptr, preamp) };
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(format!("Invalid preamp {}", preamp))
+        }
+    }
+
+    pub fn amp(&self, band: u32) -> f32 {
+        unsafe { sys::libvlc_audio_equalizer_get_amp_at_index(self.ptr, band) }
+    }
+
+    #[allow(dead_code)]
+    pub fn set_amp(&mut self, band: u32, amp: f32) -> Result<(), String> {
+        let err = unsafe { sys::libvlc_audio_equalizer_set_amp_at_index(self.ptr, amp, band) };
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(format!("Invalid band {} or amp {}", band, amp))
+        }
+    }
+}
+
+impl Drop for Equalizer {
+    fn drop(&mut self) {
+        unsafe { sys::libvlc_audio_equalizer_release(self.ptr) };
+    }
+}
+
 // For video_set_callbacks
 struct VideoCallbacksData {
-    lock: Box<dyn Fn() -> *mut c_void + Send + 'static>,
-    unlock: Option<Box<dyn Fn() + Send + 'static>>,
-    display: Option<Box<dyn Fn() + Send + 'static>>,
+    lock: Box<dyn Fn(&mut [*mut c_void; 3]) -> usize + Send + 'static>,
+    unlock: Option<Box<dyn Fn(usize) + Send + 'static>>,
+    display: Option<Box<dyn Fn(usize) + Send + 'static>>,
+    setup: Option<Box<dyn Fn(&mut VideoFormat) -> u32 + Send + 'static>>,
+    cleanup: Option<Box<dyn Fn() + Send + 'static>>,
 }
 
 unsafe extern "C" fn video_cb_lock(data: *mut c_void, planes: *mut *mut c_void) -> *mut c_void {
     let data: &VideoCallbacksData = transmute(data as *mut VideoCallbacksData);
-    *planes = (data.lock)();
-    return std::ptr::null_mut();
+    // プレーンごとのバッファを渡す (I420 なら Y, U, V)
+    let planes = &mut *(planes as *mut [*mut c_void; 3]);
+    // id をそのままピクチャーのポインターとして VLC に持たせる
+    (data.lock)(planes) as *mut c_void
 }
 
 unsafe extern "C" fn video_cb_unlock(
     data: *mut c_void,
-    _picture: *mut c_void,
+    picture: *mut c_void,
     _planes: *const *mut c_void,
 ) {
     let data: &VideoCallbacksData = transmute(data as *mut VideoCallbacksData);
-    (data.unlock.as_ref().unwrap())();
+    (data.unlock.as_ref().unwrap())(picture as usize);
 }
 
-unsafe extern "C" fn video_cb_display(data: *mut c_void, _picture: *mut c_void) {
+unsafe extern "C" fn video_cb_display(data: *mut c_void, picture: *mut c_void) {
     let data: &VideoCallbacksData = transmute(data as *mut VideoCallbacksData);
-    (data.display.as_ref().unwrap())();
+    (data.display.as_ref().unwrap())(picture as usize);
+}
+
+unsafe extern "C" fn video_cb_setup(
+    opaque: *mut *mut c_void,
+    chroma: *mut c_char,
+    width: *mut c_uint,
+    height: *mut c_uint,
+    pitches: *mut c_uint,
+    lines: *mut c_uint,
+) -> c_uint {
+    let data: &VideoCallbacksData = transmute(*opaque as *mut VideoCallbacksData);
+    let chroma = std::slice::from_raw_parts_mut(chroma as *mut u8, 4);
+    let pitches = std::slice::from_raw_parts_mut(pitches, 3);
+    let lines = std::slice::from_raw_parts_mut(lines, 3);
+
+    let mut format = VideoFormat {
+        chroma: [chroma[0], chroma[1], chroma[2], chroma[3]],
+        width: *width,
+        height: *height,
+        pitches: [0; 3],
+        lines: [0; 3],
+    };
+    let count = (data.setup.as_ref().unwrap())(&mut format);
+
+    chroma.copy_from_slice(&format.chroma);
+    *width = format.width;
+    *height = format.height;
+    pitches.copy_from_slice(&format.pitches);
+    lines.copy_from_slice(&format.lines);
+    count
+}
+
+unsafe extern "C" fn video_cb_cleanup(opaque: *mut c_void) {
+    let data: &VideoCallbacksData = transmute(opaque as *mut VideoCallbacksData);
+    (data.cleanup.as_ref().unwrap())();
+}
+
+// For audio_set_callbacks
+struct AudioCallbacksData {
+    play: Box<dyn Fn(*const c_void, u32, i64) + Send + 'static>,
+    pause: Option<Box<dyn Fn(i64) + Send + 'static>>,
+    resume: Option<Box<dyn Fn(i64) + Send + 'static>>,
+    flush: Option<Box<dyn Fn(i64) + Send + 'static>>,
+    drain: Option<Box<dyn Fn() + Send + 'static>>,
+    set_volume: Option<Box<dyn Fn(f32, bool) + Send + 'static>>,
+    setup: Option<Box<dyn Fn(&mut AudioFormat) -> bool + Send + 'static>>,
+    cleanup: Option<Box<dyn Fn() + Send + 'static>>,
+}
+
+unsafe extern "C" fn audio_cb_play(data: *mut c_void, samples: *const c_void, count: c_uint, pts: i64) {
+    let data: &AudioCallbacksData = transmute(data as *mut AudioCallbacksData);
+    (data.play)(samples, count, pts);
+}
+
+unsafe extern "C" fn audio_cb_pause(data: *mut c_void, pts: i64) {
+    let data: &AudioCallbacksData = transmute(data as *mut AudioCallbacksData);
+    (data.pause.as_ref().unwrap())(pts);
+}
+
+unsafe extern "C" fn audio_cb_resume(data: *mut c_void, pts: i64) {
+    let data: &AudioCallbacksData = transmute(data as *mut AudioCallbacksData);
+    (data.resume.as_ref().unwrap())(pts);
+}
+
+unsafe extern "C" fn audio_cb_flush(data: *mut c_void, pts: i64) {
+    let data: &AudioCallbacksData = transmute(data as *mut AudioCallbacksData);
+    (data.flush.as_ref().unwrap())(pts);
+}
+
+unsafe extern "C" fn audio_cb_drain(data: *mut c_void) {
+    let data: &AudioCallbacksData = transmute(data as *mut AudioCallbacksData);
+    (data.drain.as_ref().unwrap())();
+}
+
+unsafe extern "C" fn audio_cb_set_volume(data: *mut c_void, volume: c_float, mute: bool) {
+    let data: &AudioCallbacksData = transmute(data as *mut AudioCallbacksData);
+    (data.set_volume.as_ref().unwrap())(volume, mute);
+}
+
+unsafe extern "C" fn audio_cb_setup(
+    opaque: *mut *mut c_void,
+    format: *mut c_char,
+    rate: *mut c_uint,
+    channels: *mut c_uint,
+) -> c_int {
+    let data: &AudioCallbacksData = transmute(*opaque as *mut AudioCallbacksData);
+    let format = std::slice::from_raw_parts_mut(format as *mut u8, 4);
+
+    let mut audio_format = AudioFormat {
+        format: [format[0], format[1], format[2], format[3]],
+        rate: *rate,
+        channels: *channels,
+    };
+    if !(data.setup.as_ref().unwrap())(&mut audio_format) {
+        return -1;
+    }
+
+    format.copy_from_slice(&audio_format.format);
+    *rate = audio_format.rate;
+    *channels = audio_format.channels;
+    0
+}
+
+unsafe extern "C" fn audio_cb_cleanup(opaque: *mut c_void) {
+    let data: &AudioCallbacksData = transmute(opaque as *mut AudioCallbacksData);
+    (data.cleanup.as_ref().unwrap())();
 }
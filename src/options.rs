@@ -0,0 +1,143 @@
+use std::str::FromStr;
+
+use crate::audio::{Overflow, PoolConfig, MIN_LATENCY_MS};
+use crate::dsp::DspKind;
+use crate::effects::{self, EffectConfig, REVERB_PRESETS};
+use crate::sfx::SoundSpec;
+use crate::silence::SilenceConfig;
+use crate::sink::AudioOutput;
+use crate::support::ColorMatrix;
+
+pub struct Options {
+    // 順番に再生する (--end next)
+    pub paths: Vec<String>,
+    pub pool: PoolConfig,
+    pub end: EndAction,
+    // 出力デバイスの名前 (一部でもよい)
+    pub device: Option<String>,
+    pub list_devices: bool,
+    pub audio: AudioOutput,
+    pub effects: EffectConfig,
+    // 順番は Chain::new で決まる
+    pub dsp: Vec<DspKind>,
+    pub silence: SilenceConfig,
+    // 数字キーの 1 から順に割り当てる効果音
+    pub sounds: Vec<SoundSpec>,
+    // 画面の更新に合わせて swap_buffers を待つ
+    pub vsync: bool,
+    // 解像度から推測した YUV の変換行列の代わりに使う (C キーで変えられる)
+    pub color_matrix: Option<ColorMatrix>,
+}
+
+// 最後まで再生したときの動作
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndAction {
+    Exit,
+    // 同じものをもう一度再生する
+    Loop,
+    // 次のファイルを再生する、最後のファイルのあとは Hold と同じ
+    Next,
+    // 最後のフレームを表示したままにする
+    Hold,
+}
+
+impl FromStr for EndAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<EndAction, ()> {
+        match s {
+            "exit" => Ok(EndAction::Exit),
+            "loop" => Ok(EndAction::Loop),
+            "next" => Ok(EndAction::Next),
+            "hold" => Ok(EndAction::Hold),
+            _ => Err(()),
+        }
+    }
+}
+
+const USAGE: &str =
+    "usage: opengltest [--latency <ms>] [--buffers <count>] [--drop] [--end exit|loop|next|hold] [--device <name>] [--list-devices] [--audio openal|null|wav:<file>] [--hrtf] [--reverb <preset>] [--dsp compressor,limiter,loudness] [--skip-silence] [--silence-threshold <dB>] [--silence-duration <ms>] [--sound <file>[@<priority>]]... [--vsync] [--color-matrix 601|709|2020] <media>...";
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut paths = Vec::new();
+        let mut pool = PoolConfig::default();
+        let mut end = EndAction::Exit;
+        let mut device = None;
+        let mut list_devices = false;
+        let mut audio = AudioOutput::OpenAl;
+        let mut effects = EffectConfig::default();
+        let mut dsp = Vec::new();
+        let mut silence = SilenceConfig::default();
+        let mut sounds = Vec::new();
+        let mut vsync = false;
+        let mut color_matrix = None;
+
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--latency" => pool.target_latency_ms = parse_value(arg, args.next())?,
+                "--buffers" => pool.buffers = parse_value(arg, args.next())?,
+                "--drop" => pool.overflow = Overflow::Drop,
+                "--end" => end = parse_value(arg, args.next())?,
+                "--device" => device = Some(parse_value(arg, args.next())?),
+                "--list-devices" => list_devices = true,
+                "--audio" => audio = parse_value(arg, args.next())?,
+                "--hrtf" => effects.hrtf = true,
+                "--reverb" => {
+                    let name: String = parse_value(arg, args.next())?;
+                    let preset = effects::find_reverb(&name).ok_or_else(|| {
+                        let names: Vec<&str> = REVERB_PRESETS.iter().map(|(name, _)| *name).collect();
+                        format!("Unknown reverb preset {}\npresets: {}", name, names.join(", "))
+                    })?;
+                    effects.reverb = Some(preset);
+                }
+                "--dsp" => {
+                    let names: String = parse_value(arg, args.next())?;
+                    dsp = names
+                        .split(',')
+                        .map(|name| name.parse().map_err(|_| format!("Unknown DSP {}\n{}", name, USAGE)))
+                        .collect::<Result<_, _>>()?;
+                }
+                "--skip-silence" => silence.enabled = true,
+                "--silence-threshold" => silence.threshold_db = parse_value(arg, args.next())?,
+                "--silence-duration" => silence.min_duration_ms = parse_value(arg, args.next())?,
+                "--sound" => sounds.push(parse_value(arg, args.next())?),
+                "--vsync" => vsync = true,
+                "--color-matrix" => color_matrix = Some(parse_value(arg, args.next())?),
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}\n{}", arg, USAGE)),
+                _ => paths.push(arg.clone()),
+            }
+        }
+
+        if pool.buffers == 0 {
+            return Err(format!("--buffers must be at least 1\n{}", USAGE));
+        }
+        if pool.target_latency_ms < MIN_LATENCY_MS {
+            return Err(format!("--latency must be at least {}ms\n{}", MIN_LATENCY_MS, USAGE));
+        }
+        if paths.is_empty() && !list_devices {
+            return Err(format!("No media file specified\n{}", USAGE));
+        }
+        Ok(Options {
+            paths,
+            pool,
+            end,
+            device,
+            list_devices,
+            audio,
+            effects,
+            dsp,
+            silence,
+            sounds,
+            vsync,
+            color_matrix,
+        })
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: Option<&String>) -> Result<T, String> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or(format!("Invalid value for {}\n{}", name, USAGE))
+}
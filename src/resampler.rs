@@ -0,0 +1,214 @@
+use std::f64::consts::PI;
+
+// 片側のゼロ交差の数 (多いほど遷移帯域が狭くなる)
+const ZERO_CROSSINGS: usize = 32;
+// フィルタ係数をあらかじめ計算しておく位相の数
+const PHASES: usize = 256;
+// ナイキスト周波数に対するカットオフの位置
+const ROLLOFF: f64 = 0.95;
+const KAISER_BETA: f64 = 8.0;
+
+// カイザー窓をかけた sinc による帯域制限付きのリサンプラー
+// パケットをまたいで使えるように入力の末尾を保持する
+pub struct Resampler {
+    channels: usize,
+    // 出力 1 フレームあたりに進む入力フレーム数
+    ratio: f64,
+    // 片側のタップ数
+    half: usize,
+    // (PHASES + 1) 行 × (half * 2) 列
+    table: Vec<f32>,
+    input: Vec<f32>,
+    // 次に出力する位置 (input の先頭からのフレーム数)
+    position: f64,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32, channels: u32) -> Resampler {
+        // ダウンサンプリングのときは出力のナイキスト周波数で切る
+        let cutoff = (to as f64 / from as f64).min(1.0) * ROLLOFF;
+        let half = (ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+        let taps = half * 2;
+
+        let mut table = Vec::with_capacity((PHASES + 1) * taps);
+        for phase in 0..=PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            let row: Vec<f64> = (0..taps)
+                .map(|k| {
+                    let x = k as f64 - (half - 1) as f64 - frac;
+                    cutoff * sinc(cutoff * x) * kaiser(x / half as f64)
+                })
+                .collect();
+            // 直流のゲインを 1 にそろえる
+            let sum: f64 = row.iter().sum();
+            table.extend(row.iter().map(|tap| (tap / sum) as f32));
+        }
+
+        let mut resampler = Resampler {
+            channels: channels as usize,
+            ratio: from as f64 / to as f64,
+            half,
+            table,
+            input: Vec::new(),
+            position: 0.0,
+        };
+        resampler.reset();
+        resampler
+    }
+
+    // flush したときなど、前のパケットとつながらないとき
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.input.resize((self.half - 1) * self.channels, 0.0);
+        self.position = (self.half - 1) as f64;
+    }
+
+    // インターリーブされたサンプルを変換する
+    // 出力は入力の末尾 half フレーム分だけ遅れる
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        let channels = self.channels;
+        let taps = self.half * 2;
+        self.input.extend_from_slice(samples);
+        let frames = self.input.len() / channels;
+
+        let mut output = Vec::with_capacity(((samples.len() / channels) as f64 / self.ratio) as usize * channels + channels);
+        loop {
+            let index = self.position as usize;
+            if index + self.half >= frames {
+                break;
+            }
+            let phase = (self.position - index as f64) * PHASES as f64;
+            let phase_index = phase as usize;
+            let blend = (phase - phase_index as f64) as f32;
+            let row0 = &self.table[phase_index * taps..(phase_index + 1) * taps];
+            let row1 = &self.table[(phase_index + 1) * taps..(phase_index + 2) * taps];
+
+            let start = (index + 1 - self.half) * channels;
+            for channel in 0..channels {
+                let mut sum = 0.0;
+                for k in 0..taps {
+                    let tap = row0[k] + (row1[k] - row0[k]) * blend;
+                    sum += self.input[start + k * channels + channel] * tap;
+                }
+                output.push(sum);
+            }
+            self.position += self.ratio;
+        }
+
+        // もう使わない入力を捨てる
+        let consumed = (self.position as usize).saturating_sub(self.half - 1).min(frames);
+        self.input.drain(..consumed * channels);
+        self.position -= consumed as f64;
+        output
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// x は -1.0 〜 1.0
+fn kaiser(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    bessel_i0(KAISER_BETA * (1.0 - x * x).sqrt()) / bessel_i0(KAISER_BETA)
+}
+
+// 第 1 種変形ベッセル関数 I0
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let y = x * x / 4.0;
+    for k in 1..50 {
+        term *= y / (k * k) as f64;
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (2.0 * PI * freq * i as f64 / rate as f64).sin() as f32)
+            .collect()
+    }
+
+    // 立ち上がりと末尾を除いた RMS
+    fn rms(samples: &[f32]) -> f64 {
+        let samples = &samples[samples.len() / 4..samples.len() * 3 / 4];
+        (samples.iter().map(|&s| (s as f64).powi(2)).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn output_length_follows_ratio() {
+        let mut resampler = Resampler::new(48000, 44100, 2);
+        let output = resampler.process(&vec![0.0; 48000 * 2]);
+        let frames = output.len() / 2;
+        assert!((frames as i64 - 44100).abs() <= resampler.half as i64 * 2, "{}", frames);
+    }
+
+    #[test]
+    fn keeps_dc() {
+        let mut resampler = Resampler::new(44100, 48000, 1);
+        let output = resampler.process(&vec![0.5; 4410]);
+        for &sample in &output[resampler.half * 2..] {
+            assert!((sample - 0.5).abs() < 1e-3, "{}", sample);
+        }
+    }
+
+    #[test]
+    fn passes_tones_below_nyquist() {
+        let mut resampler = Resampler::new(48000, 44100, 1);
+        let output = resampler.process(&sine(1000.0, 48000, 48000));
+        let level = rms(&output);
+        assert!((level - std::f64::consts::FRAC_1_SQRT_2).abs() < 0.01, "{}", level);
+    }
+
+    #[test]
+    fn rejects_tones_above_output_nyquist() {
+        // 出力のナイキスト周波数 (22050Hz) を超える音は折り返さずに消える
+        let mut resampler = Resampler::new(48000, 44100, 1);
+        let output = resampler.process(&sine(23500.0, 48000, 48000));
+        let level = rms(&output);
+        assert!(level < 0.01, "{}", level);
+    }
+
+    #[test]
+    fn streaming_matches_one_shot() {
+        let input = sine(440.0, 44100, 4410);
+        let mut one_shot = Resampler::new(44100, 48000, 1);
+        let expected = one_shot.process(&input);
+
+        let mut streaming = Resampler::new(44100, 48000, 1);
+        let mut output = Vec::new();
+        for chunk in input.chunks(441) {
+            output.extend(streaming.process(chunk));
+        }
+        assert_eq!(output.len(), expected.len());
+        for (a, b) in output.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn keeps_channels_separate() {
+        let mut resampler = Resampler::new(44100, 48000, 2);
+        let input: Vec<f32> = (0..4410).flat_map(|_| [0.25, -0.75]).collect();
+        let output = resampler.process(&input);
+        for frame in output[resampler.half * 4..].chunks(2) {
+            assert!((frame[0] - 0.25).abs() < 1e-3);
+            assert!((frame[1] + 0.75).abs() < 1e-3);
+        }
+    }
+}
@@ -0,0 +1,398 @@
+use alto::{Buffer, Context, Mono, Source, SourceState, StaticSource, Stereo};
+use std::fs::{self, File};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use lewton::inside_ogg::OggStreamReader;
+
+// 同時に鳴らせる効果音の数
+const VOICES: usize = 16;
+
+// fmt チャンクの形式が cbSize 以降のサブフォーマットにある
+pub const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+// "path" か "path@priority" (priority が大きいものほど止められにくい)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SoundSpec {
+    pub path: String,
+    pub priority: u8,
+}
+
+impl FromStr for SoundSpec {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<SoundSpec, ()> {
+        let (path, priority) = match s.rsplit_once('@') {
+            Some((path, priority)) => match priority.parse() {
+                Ok(priority) => (path, priority),
+                // パスに @ が入っているだけ
+                Err(_) => (s, 0),
+            },
+            None => (s, 0),
+        };
+        if path.is_empty() {
+            return Err(());
+        }
+        Ok(SoundSpec {
+            path: path.to_string(),
+            priority,
+        })
+    }
+}
+
+// デコードした効果音
+// デバイスを切り替えたときにバッファを作り直せるように PCM のまま持っておく
+#[derive(Debug, PartialEq)]
+pub struct SoundData {
+    pub samples: Vec<i16>,
+    pub channels: u16,
+    pub rate: u32,
+}
+
+impl SoundData {
+    pub fn load(path: &str) -> Result<SoundData, String> {
+        let extension = Path::new(path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        let result = match extension.as_deref() {
+            Some("wav") => fs::read(path).map_err(|err| err.to_string()).and_then(|bytes| parse_wav(&bytes)),
+            Some("ogg") => decode_ogg(path),
+            _ => Err("only WAV and OGG are supported".to_string()),
+        };
+        result.map_err(|err| format!("Failed to load {}: {}", path, err))
+    }
+}
+
+// 8/16 ビット PCM と 32 ビット float の WAV を 16 ビットにして読む
+fn parse_wav(bytes: &[u8]) -> Result<SoundData, String> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err("not a WAV file".to_string());
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+    let mut format = None;
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let id = &bytes[offset..offset + 4];
+        let size = u32_at(offset + 4) as usize;
+        let body = offset + 8;
+        let end = (body + size).min(bytes.len());
+        match id {
+            b"fmt " if size >= 16 => {
+                if body + 16 > bytes.len() {
+                    return Err("truncated fmt chunk".to_string());
+                }
+                let mut tag = u16_at(body);
+                if tag == WAVE_FORMAT_EXTENSIBLE {
+                    // サブフォーマットの GUID の先頭 2 バイトが本当の形式
+                    if size < 40 || body + 26 > bytes.len() {
+                        return Err("truncated fmt chunk".to_string());
+                    }
+                    tag = u16_at(body + 24);
+                }
+                // (形式, チャンネル数, 周波数, ビット数)
+                format = Some((tag, u16_at(body + 2), u32_at(body + 4), u16_at(body + 14)));
+            }
+            b"data" => {
+                let (tag, channels, rate, bits) = format.ok_or("no fmt chunk before data")?;
+                let data = &bytes[body..end];
+                let samples = match (tag, bits) {
+                    (1, 8) => data.iter().map(|&sample| (sample as i16 - 128) << 8).collect(),
+                    (1, 16) => data.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]])).collect(),
+                    (3, 32) => data
+                        .chunks_exact(4)
+                        .map(|sample| {
+                            let sample = f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]);
+                            (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+                        })
+                        .collect(),
+                    _ => return Err(format!("unsupported WAV format {} ({} bits)", tag, bits)),
+                };
+                if channels != 1 && channels != 2 {
+                    return Err(format!("unsupported channel count {}", channels));
+                }
+                return Ok(SoundData { samples, channels, rate });
+            }
+            _ => (),
+        }
+        // チャンクは 2 バイト境界にそろえられている
+        offset = body + size + (size & 1);
+    }
+    Err("no data chunk".to_string())
+}
+
+fn decode_ogg(path: &str) -> Result<SoundData, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut reader = OggStreamReader::new(file).map_err(|err| err.to_string())?;
+    let channels = reader.ident_hdr.audio_channels as u16;
+    if channels != 1 && channels != 2 {
+        return Err(format!("unsupported channel count {}", channels));
+    }
+    let mut samples = Vec::new();
+    while let Some(packet) = reader.read_dec_packet_itl().map_err(|err| err.to_string())? {
+        samples.extend(packet);
+    }
+    Ok(SoundData {
+        samples,
+        channels,
+        rate: reader.ident_hdr.audio_sample_rate,
+    })
+}
+
+pub struct Sound {
+    pub name: String,
+    pub data: SoundData,
+    pub priority: u8,
+}
+
+// 数字キーの 1 から順に割り当てる効果音
+pub struct SoundBank {
+    sounds: Vec<Sound>,
+}
+
+impl SoundBank {
+    pub fn load(specs: &[SoundSpec]) -> Result<SoundBank, String> {
+        let sounds = specs
+            .iter()
+            .map(|spec| {
+                let data = SoundData::load(&spec.path)?;
+                let name = Path::new(&spec.path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| spec.path.clone());
+                println!(
+                    "sound {}: {} ({}ch {}Hz, priority {})",
+                    name, spec.path, data.channels, data.rate, spec.priority
+                );
+                Ok(Sound {
+                    name,
+                    data,
+                    priority: spec.priority,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(SoundBank { sounds })
+    }
+
+    pub fn sounds(&self) -> &[Sound] {
+        &self.sounds
+    }
+}
+
+// (鳴っているか, 優先度, 鳴らし始めた時刻) から使うボイスを選ぶ
+// 空いていなければ優先度が一番低く (同じなら一番古い) ものを止める、どれも高ければ鳴らさない
+fn pick_voice(voices: &[(bool, u8, Instant)], priority: u8) -> Option<usize> {
+    if let Some(free) = voices.iter().position(|&(playing, _, _)| !playing) {
+        return Some(free);
+    }
+    voices
+        .iter()
+        .enumerate()
+        .filter(|(_, &(_, voice_priority, _))| voice_priority <= priority)
+        .min_by_key(|(_, &(_, voice_priority, started))| (voice_priority, started))
+        .map(|(index, _)| index)
+}
+
+struct Voice {
+    source: StaticSource,
+    priority: u8,
+    started: Instant,
+}
+
+// 映像の音と同じコンテキストで鳴らす効果音
+// 映像の音量とは別に volume で音量を決める
+pub struct SoundEffects {
+    bank: Arc<SoundBank>,
+    buffers: Vec<Arc<Buffer>>,
+    voices: Vec<Voice>,
+    volume: f32,
+}
+
+impl SoundEffects {
+    pub fn new(context: &Context, bank: Arc<SoundBank>, volume: f32) -> Result<SoundEffects, String> {
+        let buffers = bank
+            .sounds()
+            .iter()
+            .map(|sound| {
+                let data = &sound.data;
+                let buffer = if data.channels == 1 {
+                    context.new_buffer::<Mono<i16>, _>(&data.samples[..], data.rate as i32)
+                } else {
+                    context.new_buffer::<Stereo<i16>, _>(&data.samples[..], data.rate as i32)
+                };
+                buffer
+                    .map(Arc::new)
+                    .map_err(|err| format!("Failed to create buffer for {}: {}", sound.name, err))
+            })
+            .collect::<Result<_, String>>()?;
+
+        let now = Instant::now();
+        let mut voices = Vec::with_capacity(VOICES);
+        for _ in 0..VOICES {
+            let mut source = context.new_static_source().map_err(|err| err.to_string())?;
+            // 効果音は空間化しない
+            source.set_relative(true);
+            source.set_gain(volume).ok();
+            voices.push(Voice {
+                source,
+                priority: 0,
+                started: now,
+            });
+        }
+        Ok(SoundEffects {
+            bank,
+            buffers,
+            voices,
+            volume,
+        })
+    }
+
+    pub fn bank(&self) -> Arc<SoundBank> {
+        Arc::clone(&self.bank)
+    }
+
+    pub fn play(&mut self, index: usize) -> Result<(), String> {
+        let sound = self.bank.sounds().get(index).ok_or(format!("No sound {}", index + 1))?;
+        let states: Vec<(bool, u8, Instant)> = self
+            .voices
+            .iter()
+            .map(|voice| (voice.source.state() == SourceState::Playing, voice.priority, voice.started))
+            .collect();
+        let voice = match pick_voice(&states, sound.priority) {
+            Some(voice) => &mut self.voices[voice],
+            None => return Err(format!("sound {}: all voices are busy with higher priority", sound.name)),
+        };
+        if states.iter().all(|&(playing, _, _)| playing) {
+            println!("sound {}: stealing a voice with priority {}", sound.name, voice.priority);
+        }
+
+        voice.source.stop();
+        voice
+            .source
+            .set_buffer(Arc::clone(&self.buffers[index]))
+            .map_err(|(err, _)| err.to_string())?;
+        voice.source.set_gain(self.volume).ok();
+        voice.source.play();
+        voice.priority = sound.priority;
+        voice.started = Instant::now();
+        Ok(())
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+        for voice in self.voices.iter_mut() {
+            voice.source.set_gain(volume).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn wav(tag: u16, channels: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"RIFF\0\0\0\0WAVE".to_vec();
+        // 知らないチャンクは飛ばす (奇数の長さは 1 バイト詰める)
+        bytes.extend(b"LIST\x03\0\0\0abc\0");
+        bytes.extend(b"fmt \x10\0\0\0");
+        bytes.extend(tag.to_le_bytes());
+        bytes.extend(channels.to_le_bytes());
+        bytes.extend(22050u32.to_le_bytes());
+        bytes.extend((22050 * (channels * bits / 8) as u32).to_le_bytes());
+        bytes.extend((channels * bits / 8).to_le_bytes());
+        bytes.extend(bits.to_le_bytes());
+        bytes.extend(b"data");
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn parses_pcm16_wav() {
+        let data: Vec<u8> = [1000i16, -1000, 32767, -32768].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let sound = parse_wav(&wav(1, 2, 16, &data)).unwrap();
+        assert_eq!(
+            sound,
+            SoundData {
+                samples: vec![1000, -1000, 32767, -32768],
+                channels: 2,
+                rate: 22050,
+            }
+        );
+    }
+
+    #[test]
+    fn converts_8bit_and_float_wav() {
+        let sound = parse_wav(&wav(1, 1, 8, &[128, 255, 0])).unwrap();
+        assert_eq!(sound.samples, vec![0, 127 << 8, -128 << 8]);
+        let data: Vec<u8> = [0.5f32, -2.0].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let sound = parse_wav(&wav(3, 1, 32, &data)).unwrap();
+        assert_eq!(sound.samples, vec![16383, -32767]);
+    }
+
+    #[test]
+    fn parses_extensible_wav() {
+        let mut bytes = b"RIFF\0\0\0\0WAVEfmt \x28\0\0\0".to_vec();
+        bytes.extend(WAVE_FORMAT_EXTENSIBLE.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(44100u32.to_le_bytes());
+        bytes.extend((44100 * 2u32).to_le_bytes());
+        bytes.extend(2u16.to_le_bytes());
+        bytes.extend(16u16.to_le_bytes());
+        // cbSize, 有効ビット数, チャンネルマスク, KSDATAFORMAT_SUBTYPE_PCM
+        bytes.extend(22u16.to_le_bytes());
+        bytes.extend(16u16.to_le_bytes());
+        bytes.extend(4u32.to_le_bytes());
+        bytes.extend(b"\x01\0\0\0\0\0\x10\0\x80\0\0\xaa\0\x38\x9b\x71");
+        bytes.extend(b"data\x04\0\0\0");
+        bytes.extend([1000i16, -1000].iter().flat_map(|sample| sample.to_le_bytes()));
+        let sound = parse_wav(&bytes).unwrap();
+        assert_eq!(sound.samples, vec![1000, -1000]);
+        assert_eq!(sound.rate, 44100);
+    }
+
+    #[test]
+    fn rejects_truncated_fmt() {
+        let bytes = wav(1, 1, 16, &[]);
+        // LIST チャンクのあと fmt の途中で切れている
+        let fmt = 12 + 12 + 8;
+        assert!(parse_wav(&bytes[..fmt + 10]).is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_wav() {
+        assert!(parse_wav(b"not a wav file").is_err());
+        assert!(parse_wav(&wav(1, 1, 24, &[0; 6])).is_err());
+        assert!(parse_wav(&wav(1, 6, 16, &[0; 12])).is_err());
+    }
+
+    #[test]
+    fn parses_sound_spec() {
+        let spec: SoundSpec = "sounds/hit.wav@3".parse().unwrap();
+        assert_eq!(spec, SoundSpec { path: "sounds/hit.wav".to_string(), priority: 3 });
+        let spec: SoundSpec = "me@home/beep.ogg".parse().unwrap();
+        assert_eq!(spec, SoundSpec { path: "me@home/beep.ogg".to_string(), priority: 0 });
+        assert!("@2".parse::<SoundSpec>().is_err());
+    }
+
+    #[test]
+    fn prefers_free_voice() {
+        let now = Instant::now();
+        let voices = [(true, 0, now), (false, 5, now), (true, 0, now)];
+        assert_eq!(pick_voice(&voices, 0), Some(1));
+    }
+
+    #[test]
+    fn steals_oldest_lowest_priority_voice() {
+        let now = Instant::now();
+        let old = now - Duration::from_secs(1);
+        let voices = [(true, 2, old), (true, 1, now), (true, 1, old), (true, 3, old)];
+        assert_eq!(pick_voice(&voices, 2), Some(2));
+        // 自分より優先度の高いものしかなければ鳴らさない
+        assert_eq!(pick_voice(&voices, 0), None);
+    }
+}
@@ -0,0 +1,189 @@
+// RMS を求める単位 (ms)
+const BLOCK_MS: u32 = 20;
+
+// 無音とみなす条件と、無音のときの動作
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SilenceConfig {
+    // これより小さい RMS (dBFS) を無音とする
+    pub threshold_db: f32,
+    // これだけ続いたら速くする (ms)
+    pub min_duration_ms: u32,
+    // 起動したときから有効にする
+    pub enabled: bool,
+}
+
+impl Default for SilenceConfig {
+    fn default() -> SilenceConfig {
+        SilenceConfig {
+            threshold_db: -45.0,
+            min_duration_ms: 500,
+            enabled: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SilenceEvent {
+    // min_duration_ms 続いた
+    Start,
+    // 無音が終わった (Start のあとだけ)
+    End,
+}
+
+// play コールバックに流れるサンプルの RMS から無音を見つける
+// 長さは play に渡されたサンプル数で数える (速くしている間は鳴らした長さになる)
+pub struct SilenceDetector {
+    config: SilenceConfig,
+    rate: u32,
+    block_frames: usize,
+    // 途中までのブロック
+    frames: usize,
+    energy: f64,
+    // 続いている無音のフレーム数
+    silent_frames: u64,
+    started: bool,
+    // 有効な間に Start のあと鳴らした無音の長さ (秒)
+    skipped: f64,
+}
+
+impl SilenceDetector {
+    pub fn new(config: SilenceConfig, rate: u32) -> SilenceDetector {
+        let mut detector = SilenceDetector {
+            config,
+            rate,
+            block_frames: 0,
+            frames: 0,
+            energy: 0.0,
+            silent_frames: 0,
+            started: false,
+            skipped: 0.0,
+        };
+        detector.configure(rate);
+        detector
+    }
+
+    pub fn configure(&mut self, rate: u32) {
+        self.rate = rate;
+        self.block_frames = (rate * BLOCK_MS / 1000).max(1) as usize;
+        self.frames = 0;
+        self.energy = 0.0;
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.config.enabled = enabled;
+    }
+
+    // 速くして鳴らした時間。速度を掛ければ飛ばした時間になる
+    pub fn skipped_secs(&self) -> f64 {
+        self.skipped
+    }
+
+    fn ms_to_frames(&self, ms: u32) -> u64 {
+        ms as u64 * self.rate as u64 / 1000
+    }
+
+    // 状態が変わるたびに emit を呼ぶ
+    pub fn process(&mut self, samples: &[f32], channels: usize, mut emit: impl FnMut(SilenceEvent)) {
+        for frame in samples.chunks_exact(channels) {
+            self.energy += frame.iter().map(|&sample| (sample * sample) as f64).sum::<f64>() / channels as f64;
+            self.frames += 1;
+            if self.frames == self.block_frames {
+                let rms = (self.energy / self.frames as f64).sqrt();
+                let silent = 20.0 * rms.max(1e-10).log10() < self.config.threshold_db as f64;
+                let frames = self.frames as u64;
+                self.frames = 0;
+                self.energy = 0.0;
+                self.finish_block(silent, frames, &mut emit);
+            }
+        }
+    }
+
+    fn finish_block(&mut self, silent: bool, frames: u64, emit: &mut impl FnMut(SilenceEvent)) {
+        if !silent {
+            if self.started {
+                emit(SilenceEvent::End);
+            }
+            self.silent_frames = 0;
+            self.started = false;
+            return;
+        }
+
+        self.silent_frames += frames;
+        if !self.started {
+            if self.silent_frames >= self.ms_to_frames(self.config.min_duration_ms) {
+                self.started = true;
+                emit(SilenceEvent::Start);
+            }
+            return;
+        }
+        if self.config.enabled {
+            self.skipped += frames as f64 / self.rate as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 1000;
+
+    fn run(detector: &mut SilenceDetector, amplitude: f32, ms: u32) -> Vec<SilenceEvent> {
+        let samples: Vec<f32> = (0..ms * RATE / 1000)
+            .flat_map(|i| {
+                let sample = if i % 2 == 0 { amplitude } else { -amplitude };
+                [sample, sample]
+            })
+            .collect();
+        let mut events = Vec::new();
+        // 小さいパケットに分けて渡す
+        for packet in samples.chunks(14) {
+            detector.process(packet, 2, |event| events.push(event));
+        }
+        events
+    }
+
+    fn detector() -> SilenceDetector {
+        let config = SilenceConfig {
+            threshold_db: -40.0,
+            min_duration_ms: 200,
+            enabled: true,
+        };
+        SilenceDetector::new(config, RATE)
+    }
+
+    #[test]
+    fn short_gaps_are_ignored() {
+        let mut detector = detector();
+        assert!(run(&mut detector, 0.5, 300).is_empty());
+        assert!(run(&mut detector, 0.0, 180).is_empty());
+        assert!(run(&mut detector, 0.5, 300).is_empty());
+    }
+
+    #[test]
+    fn reports_start_and_end() {
+        let mut detector = detector();
+        run(&mut detector, 0.5, 100);
+        assert_eq!(run(&mut detector, 0.001, 400), vec![SilenceEvent::Start]);
+        assert_eq!(run(&mut detector, 0.5, 100), vec![SilenceEvent::End]);
+    }
+
+    #[test]
+    fn stays_started_while_silent() {
+        let mut detector = detector();
+        assert_eq!(run(&mut detector, 0.0, 2300), vec![SilenceEvent::Start]);
+    }
+
+    #[test]
+    fn counts_silence_after_start() {
+        let mut detector = detector();
+        run(&mut detector, 0.0, 1200);
+        run(&mut detector, 0.5, 100);
+        // 最初の 200ms は普通の速さで鳴っている
+        assert!((detector.skipped_secs() - 1.0).abs() < 1e-9);
+
+        detector.set_enabled(false);
+        run(&mut detector, 0.0, 1200);
+        assert!((detector.skipped_secs() - 1.0).abs() < 1e-9);
+    }
+}
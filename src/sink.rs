@@ -0,0 +1,595 @@
+use alto::Alto;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::audio::{self, AudioBridge, AudioQueue, OpenAlQueue};
+use crate::channels::ChannelLayout;
+use crate::clock::AudioClock;
+use crate::device;
+use crate::dsp::Chain;
+use crate::options::Options;
+use crate::sfx::{SoundBank, WAVE_FORMAT_EXTENSIBLE};
+
+// OpenAL がないときの周波数 (configure で VLC の周波数になる)
+const DEFAULT_RATE: u32 = 48000;
+
+// libvlc の音声コールバックから呼ぶ出力先
+// libvlc のスレッドとイベントループの両方から呼ぶのでロックは中で取る
+pub trait AudioSink: Send + Sync {
+    fn configure(&self, rate: u32, layout: ChannelLayout);
+    fn channels(&self) -> u32;
+    // いっぱいのときは空くまで待つ (Overflow::Drop なら捨てる)
+    fn play(&self, samples: &[f32], pts: i64);
+    fn pause(&self, pts: i64);
+    fn resume(&self, pts: i64);
+    fn flush(&self, pts: i64);
+    // 最後まで鳴らし終えるまで待つ
+    fn drain(&self);
+    // いま聞こえているサンプルの時刻 (libvlc_clock)
+    fn time(&self) -> Option<i64>;
+    // libvlc の音量 (1.0 が 100%) とミュート
+    fn set_volume(&self, volume: f32, mute: bool);
+    fn set_position(&self, position: [f32; 3], velocity: [f32; 3]);
+    fn set_spatialize(&self, enabled: bool);
+    fn set_doppler(&self, enabled: bool);
+    fn set_hrtf(&self, enabled: bool);
+    fn set_reverb(&self, preset: Option<usize>);
+    fn next_device(&self) -> Result<(), String>;
+    fn set_sounds(&self, bank: Arc<SoundBank>);
+    fn play_sound(&self, index: usize) -> Result<(), String>;
+    // 映像の音量とは別の効果音の音量 (1.0 が等倍)
+    fn set_sound_volume(&self, volume: f32);
+}
+
+// AudioQueue を AudioBridge 越しに使う出力
+pub struct QueueSink<Q: AudioQueue> {
+    bridge: Mutex<AudioBridge<Q>>,
+}
+
+impl<Q: AudioQueue> QueueSink<Q> {
+    pub fn new(bridge: AudioBridge<Q>) -> QueueSink<Q> {
+        QueueSink {
+            bridge: Mutex::new(bridge),
+        }
+    }
+}
+
+impl<Q: AudioQueue + Send> AudioSink for QueueSink<Q> {
+    fn configure(&self, rate: u32, layout: ChannelLayout) {
+        self.bridge.lock().unwrap().configure(rate, layout);
+    }
+
+    fn channels(&self) -> u32 {
+        self.bridge.lock().unwrap().channels()
+    }
+
+    fn play(&self, samples: &[f32], pts: i64) {
+        audio::play_bounded(&self.bridge, samples, pts);
+    }
+
+    fn pause(&self, pts: i64) {
+        self.bridge.lock().unwrap().pause(pts);
+    }
+
+    fn resume(&self, pts: i64) {
+        self.bridge.lock().unwrap().resume(pts);
+    }
+
+    fn flush(&self, pts: i64) {
+        self.bridge.lock().unwrap().flush(pts);
+    }
+
+    fn drain(&self) {
+        audio::drain(&self.bridge);
+    }
+
+    fn time(&self) -> Option<i64> {
+        self.bridge.lock().unwrap().queue().time()
+    }
+
+    fn set_volume(&self, volume: f32, mute: bool) {
+        self.bridge.lock().unwrap().set_volume(volume, mute);
+    }
+
+    fn set_position(&self, position: [f32; 3], velocity: [f32; 3]) {
+        self.bridge.lock().unwrap().queue_mut().set_position(position, velocity);
+    }
+
+    fn set_spatialize(&self, enabled: bool) {
+        self.bridge.lock().unwrap().queue_mut().set_spatialize(enabled);
+    }
+
+    fn set_doppler(&self, enabled: bool) {
+        self.bridge.lock().unwrap().queue_mut().set_doppler(enabled);
+    }
+
+    fn set_hrtf(&self, enabled: bool) {
+        self.bridge.lock().unwrap().queue_mut().set_hrtf(enabled);
+    }
+
+    fn set_reverb(&self, preset: Option<usize>) {
+        self.bridge.lock().unwrap().queue_mut().set_reverb(preset);
+    }
+
+    fn next_device(&self) -> Result<(), String> {
+        self.bridge.lock().unwrap().queue_mut().next_device()
+    }
+
+    fn set_sounds(&self, bank: Arc<SoundBank>) {
+        self.bridge.lock().unwrap().queue_mut().set_sounds(bank);
+    }
+
+    fn play_sound(&self, index: usize) -> Result<(), String> {
+        self.bridge.lock().unwrap().queue_mut().play_sound(index)
+    }
+
+    fn set_sound_volume(&self, volume: f32) {
+        self.bridge.lock().unwrap().queue_mut().set_sound_volume(volume);
+    }
+}
+
+// 音声の出力先
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AudioOutput {
+    OpenAl,
+    // どこにも出さないが時計は進める
+    Null,
+    // WAV ファイルに書き出す
+    Wav(String),
+}
+
+impl FromStr for AudioOutput {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<AudioOutput, ()> {
+        match s {
+            "openal" => Ok(AudioOutput::OpenAl),
+            "null" => Ok(AudioOutput::Null),
+            _ => match s.strip_prefix("wav:") {
+                Some(path) if !path.is_empty() => Ok(AudioOutput::Wav(path.to_string())),
+                _ => Err(()),
+            },
+        }
+    }
+}
+
+pub fn open(options: &Options) -> Result<Arc<dyn AudioSink>, String> {
+    let layout = ChannelLayout::Stereo;
+    let sink: Arc<dyn AudioSink> = match &options.audio {
+        AudioOutput::OpenAl => {
+            let opened = Alto::load_default()
+                .map_err(|err| format!("Failed to load OpenAL: {}", err))
+                .and_then(|alto| open_openal(&alto, options, layout));
+            match opened {
+                Ok(sink) => sink,
+                Err(err) => {
+                    // OpenAL が使えなくても映像だけは見られるようにする
+                    println!("{}, audio is disabled", err);
+                    let queue = NullQueue::new(DEFAULT_RATE, options.pool.target_latency_ms);
+                    queue_sink(AudioBridge::new(queue, DEFAULT_RATE, layout.channels()), options)
+                }
+            }
+        }
+        AudioOutput::Null => {
+            let queue = NullQueue::new(DEFAULT_RATE, options.pool.target_latency_ms);
+            queue_sink(AudioBridge::new(queue, DEFAULT_RATE, layout.channels()), options)
+        }
+        AudioOutput::Wav(path) => {
+            println!("audio output: {}", path);
+            let queue = WavQueue::new(path, DEFAULT_RATE, options.pool.target_latency_ms);
+            queue_sink(AudioBridge::new(queue, DEFAULT_RATE, layout.channels()), options)
+        }
+    };
+    Ok(sink)
+}
+
+fn open_openal(alto: &Alto, options: &Options, layout: ChannelLayout) -> Result<Arc<dyn AudioSink>, String> {
+    let al_device = device::open_output(alto, options.device.as_deref())?;
+    let al_context = audio::open_context(&al_device, options.effects.hrtf)?;
+    let device_freq = device::device_frequency(&al_device).unwrap_or(DEFAULT_RATE);
+    println!("audio device frequency: {}Hz", device_freq);
+
+    let queue = OpenAlQueue::new(al_context, device_freq, layout, options.pool, options.effects)?;
+    let mut bridge = AudioBridge::new(queue, device_freq, layout.channels());
+    bridge.set_overflow(options.pool.overflow);
+    Ok(queue_sink(bridge, options))
+}
+
+// どの出力でも同じ DSP をかける
+fn queue_sink<Q: AudioQueue + Send + 'static>(mut bridge: AudioBridge<Q>, options: &Options) -> Arc<dyn AudioSink> {
+    bridge.set_dsp(Chain::new(&options.dsp));
+    Arc::new(QueueSink::new(bridge))
+}
+
+// 実際には鳴らさず、鳴らしたことにして時間どおりに時計を進める
+pub struct NullQueue {
+    clock: AudioClock,
+    rate: u32,
+    channels: u32,
+    latency_ms: u32,
+    // 先頭のバッファの中での位置 (since の時点)
+    offset: u32,
+    // 鳴らしている間だけ Some
+    since: Option<Instant>,
+    paused: bool,
+    // 現在時刻 (テストでは手で進める)
+    now: Box<dyn Fn() -> Instant + Send>,
+}
+
+impl NullQueue {
+    pub fn new(rate: u32, latency_ms: u32) -> NullQueue {
+        NullQueue::with_time(rate, latency_ms, Box::new(Instant::now))
+    }
+
+    pub fn with_time(rate: u32, latency_ms: u32, now: Box<dyn Fn() -> Instant + Send>) -> NullQueue {
+        NullQueue {
+            clock: AudioClock::new(rate),
+            rate,
+            channels: 2,
+            latency_ms,
+            offset: 0,
+            since: None,
+            paused: false,
+            now,
+        }
+    }
+
+    // since から経過したフレーム数
+    fn elapsed_frames(&self) -> u32 {
+        self.since
+            .map(|since| ((self.now)().duration_since(since).as_micros() * self.rate as u128 / 1_000_000) as u32)
+            .unwrap_or(0)
+    }
+
+    fn position(&self, frames: u32) -> u32 {
+        (self.offset + frames).min(self.clock.queued_frames())
+    }
+
+    // 鳴らし終えたバッファを取り除く
+    fn update(&mut self) {
+        let frames = self.elapsed_frames();
+        self.offset = self.clock.consume(self.position(frames));
+        if let Some(since) = self.since.as_mut() {
+            // 端数を捨てると時計が遅れるので、進めたフレーム数の分だけ since を進める
+            *since += Duration::from_micros(frames as u64 * 1_000_000 / self.rate as u64);
+        }
+        if self.clock.queued_frames() == 0 {
+            self.since = None;
+        }
+    }
+}
+
+impl AudioQueue for NullQueue {
+    fn configure(&mut self, rate: u32, layout: ChannelLayout) {
+        self.rate = rate;
+        self.channels = layout.channels();
+        self.clear();
+    }
+
+    fn queue(&mut self, pts: i64, samples: &[f32]) {
+        self.update();
+        self.clock.queue(pts, samples.len() as u32 / self.channels);
+        if self.since.is_none() && !self.paused {
+            self.since = Some((self.now)());
+        }
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.update();
+        let pending = self.clock.queued_frames() - self.offset;
+        pending as u64 * 1000 / self.rate as u64 >= self.latency_ms as u64
+    }
+
+    fn pause(&mut self) {
+        self.update();
+        self.since = None;
+        self.paused = true;
+    }
+
+    fn resume(&mut self, delay: i64) {
+        self.paused = false;
+        self.clock.shift(delay);
+        if self.clock.queued_frames() > 0 {
+            self.since = Some((self.now)());
+        }
+    }
+
+    fn clear(&mut self) {
+        self.clock = AudioClock::new(self.rate);
+        self.offset = 0;
+        self.since = None;
+    }
+
+    fn time(&self) -> Option<i64> {
+        self.clock.time(self.position(self.elapsed_frames()) as i32)
+    }
+
+    fn is_drained(&mut self) -> bool {
+        self.update();
+        self.clock.queued_frames() == 0
+    }
+}
+
+// 鳴らしたことにしたサンプルを 16bit の WAV に書き出す
+// 時計の進め方は NullQueue と同じ
+pub struct WavQueue {
+    null: NullQueue,
+    path: String,
+    file: Option<File>,
+    rate: u32,
+    layout: ChannelLayout,
+    data_bytes: u32,
+}
+
+impl WavQueue {
+    pub fn new(path: &str, rate: u32, latency_ms: u32) -> WavQueue {
+        WavQueue {
+            null: NullQueue::new(rate, latency_ms),
+            path: path.to_string(),
+            file: None,
+            rate,
+            layout: ChannelLayout::Stereo,
+            data_bytes: 0,
+        }
+    }
+
+    fn create(&mut self) -> std::io::Result<()> {
+        let mut file = File::create(&self.path)?;
+        file.write_all(&wav_header(self.rate, self.layout, 0))?;
+        self.file = Some(file);
+        self.data_bytes = 0;
+        Ok(())
+    }
+
+    // サンプルを追記してヘッダーの長さを書き直す
+    // 終了時に閉じる機会がないので書くたびに直しておく
+    fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let bytes: Vec<u8> = self
+            .layout
+            .reorder(samples)
+            .iter()
+            .flat_map(|sample| ((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+            .collect();
+        file.write_all(&bytes)?;
+        self.data_bytes += bytes.len() as u32;
+
+        let header_bytes = wav_header_bytes(self.layout);
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&(header_bytes - 8 + self.data_bytes).to_le_bytes())?;
+        file.seek(SeekFrom::Start((header_bytes - 4) as u64))?;
+        file.write_all(&self.data_bytes.to_le_bytes())?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+// KSDATAFORMAT_SUBTYPE_PCM
+const SUBTYPE_PCM: [u8; 16] = *b"\x01\0\0\0\0\0\x10\0\x80\0\0\xaa\0\x38\x9b\x71";
+
+// WAVE_FORMAT_EXTENSIBLE のスピーカーの割り当て (OpenAL の並びと同じ順になる)
+fn channel_mask(layout: ChannelLayout) -> u32 {
+    match layout {
+        ChannelLayout::Mono => 0x4,
+        ChannelLayout::Stereo => 0x3,
+        // FL FR BL BR
+        ChannelLayout::Quad => 0x33,
+        // FL FR C LFE BL BR
+        ChannelLayout::Surround51 => 0x3f,
+        // FL FR C LFE BL BR SL SR
+        ChannelLayout::Surround71 => 0x63f,
+    }
+}
+
+// data チャンクの中身より前の長さ
+fn wav_header_bytes(layout: ChannelLayout) -> u32 {
+    if layout.is_multichannel() {
+        68
+    } else {
+        44
+    }
+}
+
+// PCM 16bit の RIFF ヘッダー
+// 3 チャンネル以上はスピーカーの割り当てを書けるように WAVE_FORMAT_EXTENSIBLE にする
+fn wav_header(rate: u32, layout: ChannelLayout, data_bytes: u32) -> Vec<u8> {
+    let channels = layout.channels();
+    let block_align = channels * 2;
+    let header_bytes = wav_header_bytes(layout);
+    let mut header = Vec::with_capacity(header_bytes as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(header_bytes - 8 + data_bytes).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&(header_bytes - 28).to_le_bytes());
+    let tag = if layout.is_multichannel() { WAVE_FORMAT_EXTENSIBLE } else { 1 };
+    header.extend_from_slice(&tag.to_le_bytes());
+    header.extend_from_slice(&(channels as u16).to_le_bytes());
+    header.extend_from_slice(&rate.to_le_bytes());
+    header.extend_from_slice(&(rate * block_align).to_le_bytes());
+    header.extend_from_slice(&(block_align as u16).to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    if layout.is_multichannel() {
+        // cbSize, 有効ビット数, チャンネルマスク, サブフォーマット
+        header.extend_from_slice(&22u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(&channel_mask(layout).to_le_bytes());
+        header.extend_from_slice(&SUBTYPE_PCM);
+    }
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_bytes.to_le_bytes());
+    header
+}
+
+impl AudioQueue for WavQueue {
+    fn configure(&mut self, rate: u32, layout: ChannelLayout) {
+        self.null.configure(rate, layout);
+        // 同じ形式なら続けて書く (ループや次のファイル)
+        if self.file.is_some() && self.rate == rate && self.layout == layout {
+            return;
+        }
+        self.rate = rate;
+        self.layout = layout;
+        if let Err(err) = self.create() {
+            println!("Failed to create {}: {}", self.path, err);
+            self.file = None;
+        }
+    }
+
+    fn queue(&mut self, pts: i64, samples: &[f32]) {
+        self.null.queue(pts, samples);
+        if let Err(err) = self.write(samples) {
+            println!("Failed to write {}: {}", self.path, err);
+            self.file = None;
+        }
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.null.is_full()
+    }
+
+    fn pause(&mut self) {
+        self.null.pause();
+    }
+
+    fn resume(&mut self, delay: i64) {
+        self.null.resume(delay);
+    }
+
+    fn clear(&mut self) {
+        self.null.clear();
+    }
+
+    fn time(&self) -> Option<i64> {
+        self.null.time()
+    }
+
+    fn is_drained(&mut self) -> bool {
+        self.null.is_drained()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 手で進める時計
+    fn null_queue() -> (NullQueue, Arc<Mutex<Instant>>) {
+        let time = Arc::new(Mutex::new(Instant::now()));
+        let now = Arc::clone(&time);
+        let mut queue = NullQueue::with_time(1000, 200, Box::new(move || *now.lock().unwrap()));
+        queue.configure(1000, ChannelLayout::Mono);
+        (queue, time)
+    }
+
+    fn advance(time: &Mutex<Instant>, ms: u64) {
+        *time.lock().unwrap() += Duration::from_millis(ms);
+    }
+
+    #[test]
+    fn null_queue_advances_in_real_time() {
+        let (mut queue, time) = null_queue();
+        queue.queue(1_000_000, &[0.0; 100]);
+        assert!(!queue.is_drained());
+        assert_eq!(queue.time(), Some(1_000_000));
+
+        advance(&time, 40);
+        assert_eq!(queue.time(), Some(1_040_000));
+        assert!(!queue.is_drained());
+        advance(&time, 60);
+        assert!(queue.is_drained());
+        assert_eq!(queue.time(), None);
+    }
+
+    #[test]
+    fn null_queue_stops_while_paused() {
+        let (mut queue, time) = null_queue();
+        queue.queue(0, &[0.0; 100]);
+        advance(&time, 30);
+        queue.pause();
+        advance(&time, 150);
+        assert_eq!(queue.time(), Some(30_000));
+        assert!(!queue.is_drained());
+
+        // 止めていた間の分だけ pts をずらして続きから鳴らす
+        queue.resume(150_000);
+        advance(&time, 20);
+        assert_eq!(queue.time(), Some(200_000));
+    }
+
+    #[test]
+    fn null_queue_stays_paused_after_clear() {
+        let (mut queue, time) = null_queue();
+        queue.queue(0, &[0.0; 100]);
+        queue.pause();
+        queue.clear();
+        queue.queue(500_000, &[0.0; 100]);
+        advance(&time, 50);
+        assert_eq!(queue.time(), Some(500_000));
+
+        queue.resume(50_000);
+        advance(&time, 20);
+        assert_eq!(queue.time(), Some(570_000));
+    }
+
+    #[test]
+    fn null_queue_is_full_at_latency() {
+        let mut queue = NullQueue::new(1000, 200);
+        queue.configure(1000, ChannelLayout::Mono);
+        queue.pause();
+        queue.queue(0, &[0.0; 100]);
+        assert!(!queue.is_full());
+        queue.queue(100_000, &[0.0; 100]);
+        assert!(queue.is_full());
+    }
+
+    #[test]
+    fn wav_queue_writes_header_and_samples() {
+        let path = std::env::temp_dir().join(format!("opengltest-{}.wav", std::process::id()));
+        let mut queue = WavQueue::new(path.to_str().unwrap(), 48000, 200);
+        queue.configure(8000, ChannelLayout::Stereo);
+        queue.queue(0, &[0.5; 160]);
+        queue.queue(10_000, &[-1.0; 160]);
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 44 + 320 * 2);
+        assert_eq!(&data[..44], &wav_header(8000, ChannelLayout::Stereo, 640)[..]);
+        assert_eq!(i16::from_le_bytes([data[44], data[45]]), i16::MAX / 2);
+        assert_eq!(i16::from_le_bytes([data[data.len() - 2], data[data.len() - 1]]), -i16::MAX);
+    }
+
+    #[test]
+    fn multichannel_wav_has_channel_mask() {
+        let header = wav_header(48000, ChannelLayout::Surround51, 1200);
+        assert_eq!(header.len(), 68);
+        let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        assert_eq!(u32_at(4), 60 + 1200);
+        assert_eq!(u32_at(16), 40);
+        assert_eq!(u16_at(20), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(u16_at(22), 6);
+        assert_eq!(u32_at(40), 0x3f);
+        assert_eq!(&header[44..60], &SUBTYPE_PCM);
+        assert_eq!(&header[60..64], b"data");
+        assert_eq!(u32_at(64), 1200);
+    }
+
+    #[test]
+    fn wav_queue_updates_multichannel_lengths() {
+        let path = std::env::temp_dir().join(format!("opengltest-51-{}.wav", std::process::id()));
+        let mut queue = WavQueue::new(path.to_str().unwrap(), 48000, 200);
+        queue.configure(8000, ChannelLayout::Surround51);
+        queue.queue(0, &[0.25; 60]);
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 68 + 120);
+        assert_eq!(&data[..68], &wav_header(8000, ChannelLayout::Surround51, 120)[..]);
+    }
+}
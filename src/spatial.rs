@@ -0,0 +1,39 @@
+use std::time::Instant;
+
+// 速度をなめらかにする割合 (1 フレームごとに新しい値をどれだけ混ぜるか)
+const VELOCITY_SMOOTHING: f64 = 0.2;
+
+// 画面上の映像の動きから音源の位置と速度を求める
+// 映像は z = -1 の平面にあり、リスナーは原点から見ている
+#[derive(Default)]
+pub struct QuadMotion {
+    last: Option<([f64; 2], Instant)>,
+    velocity: [f64; 2],
+}
+
+impl QuadMotion {
+    // center は映像の中心 (-1.0 〜 1.0)、毎フレーム呼ぶ
+    pub fn update(&mut self, center: [f64; 2]) -> ([f32; 3], [f32; 3]) {
+        let now = Instant::now();
+        if let Some((last, time)) = self.last {
+            let dt = now.duration_since(time).as_secs_f64();
+            if dt > 0.0 {
+                // マウスイベントの間隔がばらつくので急に変わらないようにする
+                for i in 0..2 {
+                    let velocity = (center[i] - last[i]) / dt;
+                    self.velocity[i] += (velocity - self.velocity[i]) * VELOCITY_SMOOTHING;
+                }
+            }
+        }
+        self.last = Some((center, now));
+        (
+            [center[0] as f32, center[1] as f32, -1.0],
+            [self.velocity[0] as f32, self.velocity[1] as f32, 0.0],
+        )
+    }
+
+    // 止まったあとも速度が 0 になるまでは update を呼び続ける
+    pub fn moving(&self) -> bool {
+        self.velocity.iter().any(|velocity| velocity.abs() > 1e-3)
+    }
+}
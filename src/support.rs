@@ -1,13 +1,427 @@
 use glutin::{self, PossiblyCurrent};
 
-use std::ffi::CStr;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::visualizer::Visualization;
+
+// VLC から受け取るピクセルフォーマット
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb24,
+    I420,
+    Nv12,
+}
+
+impl PixelFormat {
+    pub fn from_chroma(chroma: &str) -> Option<PixelFormat> {
+        match chroma.trim_end_matches('\0') {
+            "RV24" => Some(PixelFormat::Rgb24),
+            "I420" | "J420" => Some(PixelFormat::I420),
+            "NV12" => Some(PixelFormat::Nv12),
+            _ => None,
+        }
+    }
+
+    pub fn chroma(&self) -> &'static str {
+        match self {
+            PixelFormat::Rgb24 => "RV24",
+            PixelFormat::I420 => "I420",
+            PixelFormat::Nv12 => "NV12",
+        }
+    }
+
+    // VLC に出力させるクロマ。I420 に変換させるとリミテッドレンジに
+    // 詰め直されるので、J420 はそのまま受け取ってフルレンジで変換する
+    pub fn output_chroma(&self, source: &str) -> &'static str {
+        match (self, source.trim_end_matches('\0')) {
+            (PixelFormat::I420, "J420") => "J420",
+            _ => self.chroma(),
+        }
+    }
+
+    // 各プレーンの (幅, 高さ, 1ピクセルあたりのバイト数)
+    pub fn planes(&self, width: u32, height: u32) -> Vec<(u32, u32, u32)> {
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        match self {
+            PixelFormat::Rgb24 => vec![(width, height, 3)],
+            PixelFormat::I420 => vec![
+                (width, height, 1),
+                (chroma_width, chroma_height, 1),
+                (chroma_width, chroma_height, 1),
+            ],
+            PixelFormat::Nv12 => vec![(width, height, 1), (chroma_width, chroma_height, 2)],
+        }
+    }
+}
+
+// YUV → RGB の変換行列
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMatrix {
+    Bt601,
+    Bt709,
+    Bt2020,
+}
+
+impl FromStr for ColorMatrix {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ColorMatrix, ()> {
+        match s {
+            "601" => Ok(ColorMatrix::Bt601),
+            "709" => Ok(ColorMatrix::Bt709),
+            "2020" => Ok(ColorMatrix::Bt2020),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ColorMatrix {
+    // libvlc 3 のフォーマットコールバックには色空間の情報がないので、
+    // VLC と同じく 576 ラインより大きければ BT.709 とみなす。
+    // 1080 ラインを超える 10 ビットの映像は HDR として BT.2020 にする
+    // (SD の BT.709 や 8 ビットの BT.2020 は外れるので --color-matrix で指定する)
+    pub fn detect(chroma: &str, height: u32) -> ColorMatrix {
+        let deep = matches!(
+            chroma.trim_end_matches('\0'),
+            "I0AL" | "I0AB" | "I2AL" | "I2AB" | "I4AL" | "I4AB" | "P010"
+        );
+        if height > 1080 && deep {
+            ColorMatrix::Bt2020
+        } else if height > 576 {
+            ColorMatrix::Bt709
+        } else {
+            ColorMatrix::Bt601
+        }
+    }
+
+    pub fn next(&self) -> ColorMatrix {
+        match self {
+            ColorMatrix::Bt601 => ColorMatrix::Bt709,
+            ColorMatrix::Bt709 => ColorMatrix::Bt2020,
+            ColorMatrix::Bt2020 => ColorMatrix::Bt601,
+        }
+    }
+
+    // (Kr, Kb)
+    fn coefficients(&self) -> (f32, f32) {
+        match self {
+            ColorMatrix::Bt601 => (0.299, 0.114),
+            ColorMatrix::Bt709 => (0.2126, 0.0722),
+            ColorMatrix::Bt2020 => (0.2627, 0.0593),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorSpace {
+    pub matrix: ColorMatrix,
+    pub full_range: bool,
+}
+
+impl ColorSpace {
+    // 行列はデコーダーのクロマ、レンジは VLC に出力させるクロマで決める
+    pub fn detect(source: &str, output: &str, height: u32) -> ColorSpace {
+        ColorSpace {
+            matrix: ColorMatrix::detect(source, height),
+            // J420 は JPEG と同じフルレンジ
+            full_range: output.starts_with("J4"),
+        }
+    }
+
+    // vec4(y, u, v, 1) に掛ける列優先の 4x4 行列 (レンジの補正も含む)
+    pub fn yuv_to_rgb(&self) -> [f32; 16] {
+        let (kr, kb) = self.matrix.coefficients();
+        let kg = 1.0 - kr - kb;
+        let (y_scale, y_offset, c_scale) = if self.full_range {
+            (1.0, 0.0, 1.0)
+        } else {
+            (255.0 / 219.0, 16.0 / 255.0, 255.0 / 224.0)
+        };
+        let c_offset = 128.0 / 255.0;
+
+        let r_v = 2.0 * (1.0 - kr) * c_scale;
+        let g_u = -2.0 * kb * (1.0 - kb) / kg * c_scale;
+        let g_v = -2.0 * kr * (1.0 - kr) / kg * c_scale;
+        let b_u = 2.0 * (1.0 - kb) * c_scale;
+
+        let y_bias = -y_offset * y_scale;
+        #[rustfmt::skip]
+        let matrix = [
+            y_scale, y_scale, y_scale, 0.0,
+            0.0, g_u, b_u, 0.0,
+            r_v, g_v, 0.0, 0.0,
+            y_bias - r_v * c_offset,
+            y_bias - (g_u + g_v) * c_offset,
+            y_bias - b_u * c_offset,
+            1.0,
+        ];
+        matrix
+    }
+}
 
 pub struct Gl {
     pub gl: gl::Gl,
+    pub vao: u32,
+    pub program: ShaderProgram,
+    pub yuv_program: ShaderProgram,
     pub texture_id: u32,
+    pub plane_textures: [u32; 3],
+    pub texture_size: Cell<(u32, u32)>,
+    pub pixel_format: Cell<PixelFormat>,
+    pub color_ubo: u32,
+    pub color_space: Cell<Option<ColorSpace>>,
+    pub visualizer_program: ShaderProgram,
+    pub visualizer_texture: u32,
+    // GL_ARB_buffer_storage と GL_ARB_texture_storage があれば VLC に PBO へ直接書き込ませる
+    pub buffer_storage: bool,
+    // フレームキューのスロットごとの PBO
+    pub pbos: RefCell<Vec<Option<Pbo>>>,
+    // PBO から転送するテクスチャ (大きさが変わったら作り直す)
+    pub storage_textures: RefCell<Option<StorageTextures>>,
+    // 最後のフレームを PBO から転送したか
+    pub pbo_frame: Cell<bool>,
+}
+
+// 永続的にマップした PBO
+// GL は描画スレッドでしか呼べないので、フェンスの確認も描画スレッドでする
+pub struct Pbo {
+    buffer: u32,
+    fence: gl::types::GLsync,
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+}
+
+// TexStorage2D で作ったテクスチャは大きさを変えられない
+pub struct StorageTextures {
+    textures: [u32; 3],
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+}
+
+// PBO をマップしたアドレス (プレーンごと)
+// PBO を作り直すまで VLC のスレッドから書き込める
+#[derive(Clone, Copy)]
+pub struct MappedPlanes(pub [*mut libc::c_void; 3]);
+
+unsafe impl Send for MappedPlanes {}
+
+const POS_ATTRIB: u32 = 0;
+const UV_ATTRIB: u32 = 1;
+const COLOR_BLOCK_BINDING: u32 = 0;
+
+// 2D の平行移動と拡大の列優先の 4x4 行列 (頂点シェーダーの transform)
+// 画面は各軸 -1.0 〜 1.0 のままなので射影は掛けない
+pub fn transform(translate: [f32; 2], scale: [f32; 2]) -> [f32; 16] {
+    #[rustfmt::skip]
+    let matrix = [
+        scale[0], 0.0, 0.0, 0.0,
+        0.0, scale[1], 0.0, 0.0,
+        0.0, 0.0, 1.0, 0.0,
+        translate[0], translate[1], 0.0, 1.0,
+    ];
+    matrix
+}
+
+#[derive(Debug)]
+pub enum ShaderError {
+    // line はドライバーのログから分かったときだけ (行番号, その行のソース)
+    Compile {
+        stage: &'static str,
+        log: String,
+        line: Option<(usize, String)>,
+    },
+    Link {
+        log: String,
+    },
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderError::Compile { stage, log, line } => {
+                write!(f, "failed to compile {} shader: {}", stage, log.trim_end())?;
+                if let Some((number, source)) = line {
+                    write!(f, "\n  {}: {}", number, source)?;
+                }
+                Ok(())
+            }
+            ShaderError::Link { log } => write!(f, "failed to link program: {}", log.trim_end()),
+        }
+    }
 }
 
-pub fn load(gl_context: &glutin::Context<PossiblyCurrent>) -> Gl {
+// ドライバーのログから最初のエラーの行番号を探す
+// NVIDIA は "0(12) : error"、Mesa は "0:12(5): error"、AMD や Intel は "ERROR: 0:12: ..."
+fn error_line(log: &str) -> Option<usize> {
+    log.lines()
+        .filter(|line| line.to_ascii_lowercase().contains("error"))
+        .find_map(|line| {
+            let rest = line.strip_prefix("ERROR: ").unwrap_or(line);
+            let rest = rest.strip_prefix("0(").or_else(|| rest.strip_prefix("0:"))?;
+            let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse().ok()
+        })
+}
+
+fn failing_line(src: &[u8], log: &str) -> Option<(usize, String)> {
+    let number = error_line(log)?;
+    let src = String::from_utf8_lossy(src);
+    let source = src.trim_end_matches('\0').lines().nth(number.checked_sub(1)?)?;
+    Some((number, source.trim().to_string()))
+}
+
+unsafe fn shader_log(gl: &gl::Gl, shader: u32) -> String {
+    let mut len = 0;
+    gl.GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
+    let mut log = vec![0u8; len.max(1) as usize];
+    let mut written = 0;
+    gl.GetShaderInfoLog(shader, log.len() as i32, &mut written, log.as_mut_ptr() as *mut _);
+    log.truncate(written as usize);
+    String::from_utf8_lossy(&log).into_owned()
+}
+
+unsafe fn program_log(gl: &gl::Gl, program: u32) -> String {
+    let mut len = 0;
+    gl.GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
+    let mut log = vec![0u8; len.max(1) as usize];
+    let mut written = 0;
+    gl.GetProgramInfoLog(program, log.len() as i32, &mut written, log.as_mut_ptr() as *mut _);
+    log.truncate(written as usize);
+    String::from_utf8_lossy(&log).into_owned()
+}
+
+// src は NUL で終わること
+unsafe fn compile_shader(gl: &gl::Gl, kind: u32, src: &[u8]) -> Result<u32, ShaderError> {
+    let shader = gl.CreateShader(kind);
+    gl.ShaderSource(shader, 1, [src.as_ptr() as *const _].as_ptr(), std::ptr::null());
+    gl.CompileShader(shader);
+
+    let mut status = 0;
+    gl.GetShaderiv(shader, gl::COMPILE_STATUS, &mut status);
+    if status == gl::TRUE as i32 {
+        return Ok(shader);
+    }
+    let log = shader_log(gl, shader);
+    gl.DeleteShader(shader);
+    Err(ShaderError::Compile {
+        stage: if kind == gl::VERTEX_SHADER { "vertex" } else { "fragment" },
+        line: failing_line(src, &log),
+        log,
+    })
+}
+
+// リンクしたプログラムと、名前から引いた uniform / attribute の位置のキャッシュ
+pub struct ShaderProgram {
+    pub id: u32,
+    uniforms: RefCell<HashMap<&'static str, i32>>,
+    attributes: RefCell<HashMap<&'static str, i32>>,
+}
+
+impl ShaderProgram {
+    // attributes の位置はリンクの前に固定する
+    pub unsafe fn new(
+        gl: &gl::Gl,
+        vs_src: &[u8],
+        fs_src: &[u8],
+        attributes: &[(&'static str, u32)],
+    ) -> Result<ShaderProgram, ShaderError> {
+        let vs = compile_shader(gl, gl::VERTEX_SHADER, vs_src)?;
+        let fs = match compile_shader(gl, gl::FRAGMENT_SHADER, fs_src) {
+            Ok(fs) => fs,
+            Err(err) => {
+                gl.DeleteShader(vs);
+                return Err(err);
+            }
+        };
+
+        let id = gl.CreateProgram();
+        gl.AttachShader(id, vs);
+        gl.AttachShader(id, fs);
+        for (name, location) in attributes {
+            let name_c = CString::new(*name).unwrap();
+            gl.BindAttribLocation(id, *location, name_c.as_ptr());
+        }
+        gl.LinkProgram(id);
+        // リンクしたあとはシェーダーはいらない
+        gl.DetachShader(id, vs);
+        gl.DetachShader(id, fs);
+        gl.DeleteShader(vs);
+        gl.DeleteShader(fs);
+
+        let mut status = 0;
+        gl.GetProgramiv(id, gl::LINK_STATUS, &mut status);
+        if status != gl::TRUE as i32 {
+            let log = program_log(gl, id);
+            gl.DeleteProgram(id);
+            return Err(ShaderError::Link { log });
+        }
+
+        Ok(ShaderProgram {
+            id,
+            uniforms: RefCell::new(HashMap::new()),
+            attributes: RefCell::new(
+                attributes
+                    .iter()
+                    .map(|(name, location)| (*name, *location as i32))
+                    .collect(),
+            ),
+        })
+    }
+
+    // 使われていない名前は -1 (glUniform* は何もしない)
+    pub unsafe fn uniform(&self, gl: &gl::Gl, name: &'static str) -> i32 {
+        *self.uniforms.borrow_mut().entry(name).or_insert_with(|| {
+            let name_c = CString::new(name).unwrap();
+            gl.GetUniformLocation(self.id, name_c.as_ptr())
+        })
+    }
+
+    pub unsafe fn attribute(&self, gl: &gl::Gl, name: &'static str) -> i32 {
+        *self.attributes.borrow_mut().entry(name).or_insert_with(|| {
+            let name_c = CString::new(name).unwrap();
+            gl.GetAttribLocation(self.id, name_c.as_ptr())
+        })
+    }
+}
+
+// 1 ピクセルあたりのバイト数から (内部フォーマット, ピクセルフォーマット)
+fn texture_formats(bpp: u32) -> (u32, u32) {
+    match bpp {
+        1 => (gl::R8, gl::RED),
+        2 => (gl::RG8, gl::RG),
+        _ => (gl::RGB8, gl::RGB),
+    }
+}
+
+unsafe fn has_extension(gl: &gl::Gl, name: &str) -> bool {
+    let mut count = 0;
+    gl.GetIntegerv(gl::NUM_EXTENSIONS, &mut count);
+    (0..count as u32).any(|i| CStr::from_ptr(gl.GetStringi(gl::EXTENSIONS, i) as *const _).to_bytes() == name.as_bytes())
+}
+
+unsafe fn create_texture(gl: &gl::Gl) -> u32 {
+    let mut texture_id = std::mem::zeroed();
+    gl.GenTextures(1, &mut texture_id);
+    gl.BindTexture(gl::TEXTURE_2D, texture_id);
+
+    gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
+    gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+    gl.TexParameteri(
+        gl::TEXTURE_2D,
+        gl::TEXTURE_MIN_FILTER,
+        gl::LINEAR_MIPMAP_LINEAR as i32,
+    );
+    gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+    texture_id
+}
+
+pub fn load(gl_context: &glutin::Context<PossiblyCurrent>) -> Result<Gl, ShaderError> {
     let gl = gl::Gl::load_with(|ptr| gl_context.get_proc_address(ptr) as *const _);
 
     let version = unsafe {
@@ -19,30 +433,39 @@ pub fn load(gl_context: &glutin::Context<PossiblyCurrent>) -> Gl {
 
     println!("OpenGL version {}", version);
 
-    unsafe {
-        let vs = gl.CreateShader(gl::VERTEX_SHADER);
-        gl.ShaderSource(
-            vs,
-            1,
-            [VS_SRC.as_ptr() as *const _].as_ptr(),
-            std::ptr::null(),
-        );
-        gl.CompileShader(vs);
+    let buffer_storage = unsafe {
+        has_extension(&gl, "GL_ARB_buffer_storage")
+            && has_extension(&gl, "GL_ARB_texture_storage")
+            && gl.BufferStorage.is_loaded()
+            && gl.TexStorage2D.is_loaded()
+    };
+    println!("persistent mapped PBO: {}", buffer_storage);
 
-        let fs = gl.CreateShader(gl::FRAGMENT_SHADER);
-        gl.ShaderSource(
-            fs,
-            1,
-            [FS_SRC.as_ptr() as *const _].as_ptr(),
-            std::ptr::null(),
-        );
-        gl.CompileShader(fs);
+    let (vao, program, yuv_program, visualizer_program) = unsafe {
+        // コアプロファイルでは VAO がないと描けない
+        let mut vao = std::mem::zeroed();
+        gl.GenVertexArrays(1, &mut vao);
+        gl.BindVertexArray(vao);
+
+        // RGB と YUV のプログラムで同じ VBO の設定を使えるように位置を固定する
+        let attributes = [("pos", POS_ATTRIB), ("tex_coord", UV_ATTRIB)];
+
+        let program = ShaderProgram::new(&gl, VS_SRC, FS_SRC, &attributes)?;
+        gl.UseProgram(program.id);
+        gl.Uniform1i(program.uniform(&gl, "texture0"), 0);
 
-        let program = gl.CreateProgram();
-        gl.AttachShader(program, vs);
-        gl.AttachShader(program, fs);
-        gl.LinkProgram(program);
-        gl.UseProgram(program);
+        let yuv_program = ShaderProgram::new(&gl, VS_SRC, FS_YUV_SRC, &attributes)?;
+        gl.UseProgram(yuv_program.id);
+        gl.Uniform1i(yuv_program.uniform(&gl, "texture_y"), 0);
+        gl.Uniform1i(yuv_program.uniform(&gl, "texture_u"), 1);
+        gl.Uniform1i(yuv_program.uniform(&gl, "texture_v"), 2);
+        let block_index = gl.GetUniformBlockIndex(yuv_program.id, b"ColorConversion\0".as_ptr() as *const _);
+        gl.UniformBlockBinding(yuv_program.id, block_index, COLOR_BLOCK_BINDING);
+
+        let visualizer_program = ShaderProgram::new(&gl, VS_SRC, FS_VISUALIZER_SRC, &attributes)?;
+        gl.UseProgram(visualizer_program.id);
+        gl.Uniform1i(visualizer_program.uniform(&gl, "values"), 0);
+        gl.UseProgram(program.id);
 
         // VBOを生成する関数
         let mut vb = std::mem::zeroed();
@@ -66,10 +489,10 @@ pub fn load(gl_context: &glutin::Context<PossiblyCurrent>) -> Gl {
             gl::STATIC_DRAW,
         );
 
-        let pos_attrib = gl.GetAttribLocation(program, b"pos\0".as_ptr() as *const _);
-        let uv_attrib = gl.GetAttribLocation(program, b"tex_coord\0".as_ptr() as *const _);
+        let pos_attrib = program.attribute(&gl, "pos") as u32;
+        let uv_attrib = program.attribute(&gl, "tex_coord") as u32;
         gl.VertexAttribPointer(
-            pos_attrib as gl::types::GLuint,
+            pos_attrib,
             3,
             gl::FLOAT,
             0,
@@ -77,53 +500,340 @@ pub fn load(gl_context: &glutin::Context<PossiblyCurrent>) -> Gl {
             std::ptr::null(),
         );
         gl.VertexAttribPointer(
-            uv_attrib as gl::types::GLuint,
+            uv_attrib,
             2,
             gl::FLOAT,
             0,
             5 * std::mem::size_of::<f32>() as gl::types::GLsizei,
             (3 * std::mem::size_of::<f32>()) as *const () as *const _,
         );
-        gl.EnableVertexAttribArray(pos_attrib as gl::types::GLuint);
-        gl.EnableVertexAttribArray(uv_attrib as gl::types::GLuint);
-    }
+        gl.EnableVertexAttribArray(pos_attrib);
+        gl.EnableVertexAttribArray(uv_attrib);
 
-    let texture_id = unsafe {
-        let mut texture_id = std::mem::zeroed();
-        gl.ActiveTexture(gl::TEXTURE0);
-        gl.GenTextures(1, &mut texture_id);
-        gl.BindTexture(gl::TEXTURE_2D, texture_id);
+        (vao, program, yuv_program, visualizer_program)
+    };
 
-        gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
-        gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
-        gl.TexParameteri(
-            gl::TEXTURE_2D,
-            gl::TEXTURE_MIN_FILTER,
-            gl::LINEAR_MIPMAP_LINEAR as i32,
+    let color_ubo = unsafe {
+        let mut color_ubo = std::mem::zeroed();
+        gl.GenBuffers(1, &mut color_ubo);
+        gl.BindBuffer(gl::UNIFORM_BUFFER, color_ubo);
+        gl.BufferData(
+            gl::UNIFORM_BUFFER,
+            (16 * std::mem::size_of::<f32>()) as gl::types::GLsizeiptr,
+            std::ptr::null(),
+            gl::DYNAMIC_DRAW,
         );
-        gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
-        texture_id
+        gl.BindBufferBase(gl::UNIFORM_BUFFER, COLOR_BLOCK_BINDING, color_ubo);
+        color_ubo
+    };
+
+    let (texture_id, plane_textures) = unsafe {
+        gl.ActiveTexture(gl::TEXTURE0);
+        let texture_id = create_texture(&gl);
+        // YUV はプレーンごとにテクスチャを分ける
+        let plane_textures = [
+            create_texture(&gl),
+            create_texture(&gl),
+            create_texture(&gl),
+        ];
+        (texture_id, plane_textures)
     };
 
-    Gl { gl, texture_id }
+    let visualizer_texture = unsafe {
+        // 毎フレーム作り直すのでミップマップは使わない
+        let texture = create_texture(&gl);
+        gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+        gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+        texture
+    };
+
+    Ok(Gl {
+        gl,
+        vao,
+        program,
+        yuv_program,
+        texture_id,
+        plane_textures,
+        texture_size: Cell::new((0, 0)),
+        pixel_format: Cell::new(PixelFormat::Rgb24),
+        color_ubo,
+        color_space: Cell::new(None),
+        visualizer_program,
+        visualizer_texture,
+        buffer_storage,
+        pbos: RefCell::new(Vec::new()),
+        storage_textures: RefCell::new(None),
+        pbo_frame: Cell::new(false),
+    })
 }
 
 impl Gl {
+    // 映像のフォーマットが変わったときにテクスチャを確保し直す
+    pub fn resize_texture(&self, texture_width: u32, texture_height: u32) {
+        unsafe {
+            self.gl.ActiveTexture(gl::TEXTURE0);
+            self.gl.BindTexture(gl::TEXTURE_2D, self.texture_id);
+            self.gl.TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGB as i32,
+                texture_width as i32,
+                texture_height as i32,
+                0,
+                gl::RGB,
+                gl::UNSIGNED_BYTE,
+                std::ptr::null(),
+            );
+        }
+        self.texture_size.set((texture_width, texture_height));
+    }
+
     pub unsafe fn upload_texture(&self, texture_buffer: *const libc::c_void, texture_width: u32, texture_height: u32) {
+        // PBO から転送していた間はこちらのテクスチャの大きさは変わっていない
+        if self.texture_size.get() != (texture_width, texture_height) || self.pbo_frame.get() {
+            self.resize_texture(texture_width, texture_height);
+        }
         self.gl.BindTexture(gl::TEXTURE_2D, self.texture_id);
         self.gl.PixelStorei(gl::UNPACK_ALIGNMENT, 1);
-        self.gl.TexImage2D(
+        self.gl.TexSubImage2D(
             gl::TEXTURE_2D,
             0,
-            gl::RGB as i32,
+            0,
+            0,
             texture_width as i32,
             texture_height as i32,
-            0,
             gl::RGB,
             gl::UNSIGNED_BYTE,
             texture_buffer,
         );
         self.gl.GenerateMipmap(gl::TEXTURE_2D);
+        self.pixel_format.set(PixelFormat::Rgb24);
+        self.pbo_frame.set(false);
+    }
+
+    // 色空間が変わったときだけ変換行列を送り直す
+    pub fn set_color_space(&self, color_space: ColorSpace) {
+        if self.color_space.get() == Some(color_space) {
+            return;
+        }
+        println!("color space: {:?}", color_space);
+        let matrix = color_space.yuv_to_rgb();
+        unsafe {
+            self.gl.BindBuffer(gl::UNIFORM_BUFFER, self.color_ubo);
+            self.gl.BufferSubData(
+                gl::UNIFORM_BUFFER,
+                0,
+                (matrix.len() * std::mem::size_of::<f32>()) as gl::types::GLsizeiptr,
+                matrix.as_ptr() as *const _,
+            );
+        }
+        self.color_space.set(Some(color_space));
+    }
+
+    // プレーンごとにテクスチャへ転送する (色変換はシェーダーで行う)
+    pub fn upload_planes(&self, format: PixelFormat, planes: &[Vec<u8>], width: u32, height: u32) {
+        if format == PixelFormat::Rgb24 {
+            unsafe {
+                self.upload_texture(planes[0].as_ptr() as *const _, width, height);
+            }
+            return;
+        }
+
+        unsafe {
+            self.gl.PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            for (i, (plane_width, plane_height, bpp)) in format.planes(width, height).into_iter().enumerate() {
+                let (internal_format, pixel_format) = texture_formats(bpp);
+                self.gl.ActiveTexture(gl::TEXTURE0 + i as u32);
+                self.gl.BindTexture(gl::TEXTURE_2D, self.plane_textures[i]);
+                if self.texture_size.get() != (width, height) || self.pixel_format.get() != format || self.pbo_frame.get()
+                {
+                    self.gl.TexImage2D(
+                        gl::TEXTURE_2D,
+                        0,
+                        internal_format as i32,
+                        plane_width as i32,
+                        plane_height as i32,
+                        0,
+                        pixel_format,
+                        gl::UNSIGNED_BYTE,
+                        std::ptr::null(),
+                    );
+                }
+                self.gl.TexSubImage2D(
+                    gl::TEXTURE_2D,
+                    0,
+                    0,
+                    0,
+                    plane_width as i32,
+                    plane_height as i32,
+                    pixel_format,
+                    gl::UNSIGNED_BYTE,
+                    planes[i].as_ptr() as *const _,
+                );
+                self.gl.GenerateMipmap(gl::TEXTURE_2D);
+            }
+            self.gl.ActiveTexture(gl::TEXTURE0);
+        }
+        self.texture_size.set((width, height));
+        self.pixel_format.set(format);
+        self.pbo_frame.set(false);
+    }
+
+    // スロットの PBO を作り直して書き込み先を返す
+    // 前の PBO のアドレスは使えなくなるので、VLC がそのスロットに書き込んでいないときに呼ぶこと
+    // マップできなければ None (今までどおりコピーして転送する)
+    pub fn create_pbo(&self, slot: usize, format: PixelFormat, width: u32, height: u32) -> Option<MappedPlanes> {
+        self.destroy_pbo(slot);
+        let planes = format.planes(width, height);
+        let size: usize = planes.iter().map(|(w, h, bpp)| (w * h * bpp) as usize).sum();
+        let flags = gl::MAP_WRITE_BIT | gl::MAP_PERSISTENT_BIT | gl::MAP_COHERENT_BIT;
+
+        unsafe {
+            let mut buffer = std::mem::zeroed();
+            self.gl.GenBuffers(1, &mut buffer);
+            self.gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, buffer);
+            self.gl.BufferStorage(gl::PIXEL_UNPACK_BUFFER, size as isize, std::ptr::null(), flags);
+            let base = self.gl.MapBufferRange(gl::PIXEL_UNPACK_BUFFER, 0, size as isize, flags) as *mut u8;
+            self.gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0);
+            if base.is_null() {
+                println!("failed to map PBO");
+                self.gl.DeleteBuffers(1, &buffer);
+                return None;
+            }
+
+            let mut pointers = [std::ptr::null_mut(); 3];
+            let mut offset = 0;
+            for (pointer, (plane_width, plane_height, bpp)) in pointers.iter_mut().zip(&planes) {
+                *pointer = base.add(offset) as *mut _;
+                offset += (plane_width * plane_height * bpp) as usize;
+            }
+
+            let mut pbos = self.pbos.borrow_mut();
+            if pbos.len() <= slot {
+                pbos.resize_with(slot + 1, || None);
+            }
+            pbos[slot] = Some(Pbo {
+                buffer,
+                fence: std::ptr::null(),
+                format,
+                width,
+                height,
+            });
+            Some(MappedPlanes(pointers))
+        }
+    }
+
+    fn destroy_pbo(&self, slot: usize) {
+        let pbo = match self.pbos.borrow_mut().get_mut(slot).and_then(Option::take) {
+            Some(pbo) => pbo,
+            None => return,
+        };
+        unsafe {
+            self.gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, pbo.buffer);
+            self.gl.UnmapBuffer(gl::PIXEL_UNPACK_BUFFER);
+            self.gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0);
+            self.gl.DeleteBuffers(1, &pbo.buffer);
+            if !pbo.fence.is_null() {
+                self.gl.DeleteSync(pbo.fence);
+            }
+        }
+    }
+
+    // 転送先のテクスチャの大きさが違えば作り直す
+    unsafe fn prepare_storage_textures(&self, format: PixelFormat, width: u32, height: u32) -> [u32; 3] {
+        let mut storage = self.storage_textures.borrow_mut();
+        if let Some(storage) = storage.as_ref() {
+            if (storage.format, storage.width, storage.height) == (format, width, height) {
+                return storage.textures;
+            }
+            self.gl.DeleteTextures(3, storage.textures.as_ptr());
+        }
+
+        let mut textures = [0; 3];
+        self.gl.GenTextures(3, textures.as_mut_ptr());
+        for (texture, (plane_width, plane_height, bpp)) in textures.iter().zip(format.planes(width, height)) {
+            self.gl.BindTexture(gl::TEXTURE_2D, *texture);
+            // ミップマップは作らない
+            self.gl.TexStorage2D(
+                gl::TEXTURE_2D,
+                1,
+                texture_formats(bpp).0,
+                plane_width as i32,
+                plane_height as i32,
+            );
+            self.gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+            self.gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        }
+        *storage = Some(StorageTextures {
+            textures,
+            format,
+            width,
+            height,
+        });
+        textures
+    }
+
+    // VLC が書き終えたスロットの PBO をテクスチャへ転送し、GPU が読み終えたか分かるようにフェンスを置く
+    pub fn upload_pbo(&self, slot: usize) {
+        let mut pbos = self.pbos.borrow_mut();
+        let pbo = match pbos.get_mut(slot).and_then(Option::as_mut) {
+            Some(pbo) => pbo,
+            None => return,
+        };
+        unsafe {
+            let textures = self.prepare_storage_textures(pbo.format, pbo.width, pbo.height);
+            self.gl.PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            self.gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, pbo.buffer);
+            let mut offset = 0;
+            for (i, (plane_width, plane_height, bpp)) in pbo.format.planes(pbo.width, pbo.height).into_iter().enumerate() {
+                self.gl.ActiveTexture(gl::TEXTURE0 + i as u32);
+                self.gl.BindTexture(gl::TEXTURE_2D, textures[i]);
+                // PBO を束縛している間はポインターの代わりにバッファの先頭からのオフセットを渡す
+                self.gl.TexSubImage2D(
+                    gl::TEXTURE_2D,
+                    0,
+                    0,
+                    0,
+                    plane_width as i32,
+                    plane_height as i32,
+                    texture_formats(bpp).1,
+                    gl::UNSIGNED_BYTE,
+                    offset as *const _,
+                );
+                offset += (plane_width * plane_height * bpp) as usize;
+            }
+            self.gl.ActiveTexture(gl::TEXTURE0);
+            self.gl.BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0);
+
+            if !pbo.fence.is_null() {
+                self.gl.DeleteSync(pbo.fence);
+            }
+            pbo.fence = self.gl.FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0);
+        }
+        self.texture_size.set((pbo.width, pbo.height));
+        self.pixel_format.set(pbo.format);
+        self.pbo_frame.set(true);
+    }
+
+    // GPU がスロットの PBO を読み終えていれば true (待たない)
+    pub fn pbo_idle(&self, slot: usize) -> bool {
+        let mut pbos = self.pbos.borrow_mut();
+        let pbo = match pbos.get_mut(slot).and_then(Option::as_mut) {
+            Some(pbo) => pbo,
+            None => return true,
+        };
+        if pbo.fence.is_null() {
+            return true;
+        }
+        let status = unsafe { self.gl.ClientWaitSync(pbo.fence, 0, 0) };
+        if status != gl::ALREADY_SIGNALED && status != gl::CONDITION_SATISFIED {
+            return false;
+        }
+        unsafe {
+            self.gl.DeleteSync(pbo.fence);
+        }
+        pbo.fence = std::ptr::null();
+        true
     }
 
     #[allow(dead_code)]
@@ -148,24 +858,108 @@ impl Gl {
             self.gl.ClearColor(color[0], color[1], color[2], color[3]);
             self.gl.Clear(gl::COLOR_BUFFER_BIT);
 
-            self.gl.MatrixMode(gl::PROJECTION); //投影変換モードへ
-            self.gl.LoadIdentity(); //投影変換の変換行列を単位行列で初期化
-            self.gl.Ortho(-1.0, 1.0, -1.0, 1.0, 1.0, -1.0); //各軸-1.0～1.0で囲まれる立方体の範囲を並行投影
-            self.gl.MatrixMode(gl::MODELVIEW); //視野変換・モデリング変換モードへ
-            self.gl.LoadIdentity(); //視野変換・モデリング変換の変換行列を単位行列で初期化
-            self.gl.PushMatrix();
-            self.gl.Translated(pos[0] / 400.0, pos[1] / -400.0, 0.0);
-            self.gl.DrawElements(
-                gl::TRIANGLES,
-                INDEX_DATA.len() as i32,
-                gl::UNSIGNED_BYTE,
-                std::ptr::null(),
+            // PBO から転送したフレームは TexStorage2D で作ったテクスチャにある
+            let (rgb_texture, plane_textures) = match self.storage_textures.borrow().as_ref() {
+                Some(storage) if self.pbo_frame.get() => (storage.textures[0], storage.textures),
+                _ => (self.texture_id, self.plane_textures),
+            };
+            let program = match self.pixel_format.get() {
+                PixelFormat::Rgb24 => {
+                    self.gl.UseProgram(self.program.id);
+                    self.gl.ActiveTexture(gl::TEXTURE0);
+                    self.gl.BindTexture(gl::TEXTURE_2D, rgb_texture);
+                    &self.program
+                }
+                PixelFormat::I420 | PixelFormat::Nv12 => {
+                    self.gl.UseProgram(self.yuv_program.id);
+                    self.gl.Uniform1i(
+                        self.yuv_program.uniform(&self.gl, "nv12"),
+                        (self.pixel_format.get() == PixelFormat::Nv12) as i32,
+                    );
+                    for (i, texture) in plane_textures.iter().enumerate() {
+                        self.gl.ActiveTexture(gl::TEXTURE0 + i as u32);
+                        self.gl.BindTexture(gl::TEXTURE_2D, *texture);
+                    }
+                    self.gl.ActiveTexture(gl::TEXTURE0);
+                    &self.yuv_program
+                }
+            };
+
+            let center = quad_center(pos);
+            // 映像のアスペクト比を保つ
+            let (width, height) = self.texture_size.get();
+            let mut scale = [1.0, 1.0];
+            if width > 0 && height > 0 {
+                let aspect = width as f32 / height as f32;
+                if aspect >= 1.0 {
+                    scale[1] = 1.0 / aspect;
+                } else {
+                    scale[0] = aspect;
+                }
+            }
+            self.draw_quad(program, transform([center[0] as f32, center[1] as f32], scale));
+        }
+    }
+
+    // program を使っている状態で四角形を matrix の位置に描く
+    unsafe fn draw_quad(&self, program: &ShaderProgram, matrix: [f32; 16]) {
+        self.gl.UniformMatrix4fv(program.uniform(&self.gl, "transform"), 1, gl::FALSE, matrix.as_ptr());
+        self.gl.BindVertexArray(self.vao);
+        self.gl.DrawElements(
+            gl::TRIANGLES,
+            INDEX_DATA.len() as i32,
+            gl::UNSIGNED_BYTE,
+            std::ptr::null(),
+        );
+    }
+
+    // values はスペクトラムならバーの高さ、オシロスコープなら波形
+    // full なら画面全体、そうでなければ下の 1/3 に映像と重ねて描く
+    pub fn draw_visualizer(&self, visualization: Visualization, values: &[f32], full: bool) {
+        unsafe {
+            self.gl.ActiveTexture(gl::TEXTURE0);
+            self.gl.BindTexture(gl::TEXTURE_2D, self.visualizer_texture);
+            self.gl.PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+            self.gl.TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::R32F as i32,
+                values.len() as i32,
+                1,
+                0,
+                gl::RED,
+                gl::FLOAT,
+                values.as_ptr() as *const _,
+            );
+
+            self.gl.UseProgram(self.visualizer_program.id);
+            self.gl.Uniform1i(
+                self.visualizer_program.uniform(&self.gl, "scope"),
+                (visualization == Visualization::Oscilloscope) as i32,
+            );
+            self.gl.Uniform1f(
+                self.visualizer_program.uniform(&self.gl, "background"),
+                if full { 1.0 } else { 0.3 },
             );
-            self.gl.PopMatrix();
+            self.gl.Enable(gl::BLEND);
+            self.gl.BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+
+            let matrix = if full {
+                transform([0.0, 0.0], [2.0, 2.0])
+            } else {
+                transform([0.0, -2.0 / 3.0], [2.0, 2.0 / 3.0])
+            };
+            self.draw_quad(&self.visualizer_program, matrix);
+            self.gl.Disable(gl::BLEND);
         }
     }
 }
 
+// GameState.pos (カーソルの位置) から映像の中心 (-1.0 〜 1.0 の座標) を求める
+pub fn quad_center(pos: [f64; 2]) -> [f64; 2] {
+    [pos[0] / 400.0, pos[1] / -400.0]
+}
+
 #[rustfmt::skip]
 static INDEX_DATA: [u8; 6] = [
     0, 1, 2, 0, 3, 2,
@@ -180,22 +974,24 @@ static VERTEX_DATA: [f32; 20] = [
 ];
 
 const VS_SRC: &'static [u8] = b"
-#version 410 compatibility
-in vec4 pos;
+#version 330 core
+in vec3 pos;
 in vec2 tex_coord;
 
+uniform mat4 transform;
+
 out vec2 texture_coord;
 
 void main()
 {
-    gl_Position = gl_ModelViewProjectionMatrix * pos;
+    gl_Position = transform * vec4(pos, 1.0);
     //gl_Position = pos;
     texture_coord = tex_coord;
 }
 \0";
 
 const FS_SRC: &'static [u8] = b"
-#version 410 compatibility
+#version 330 core
 out vec4 FragColor;
 
 in vec2 texture_coord;
@@ -208,3 +1004,156 @@ void main()
     //FragColor = vec4(texture_coord.x, texture_coord.y, 0.0, 1.0);
 }
 \0";
+
+// プレーンごとの Y, U, V (NV12 の場合は Y, UV) テクスチャから RGB に変換する
+const FS_YUV_SRC: &'static [u8] = b"
+#version 330 core
+out vec4 FragColor;
+
+in vec2 texture_coord;
+
+uniform sampler2D texture_y;
+uniform sampler2D texture_u;
+uniform sampler2D texture_v;
+uniform bool nv12;
+
+layout(std140) uniform ColorConversion
+{
+    mat4 yuv_to_rgb;
+};
+
+void main()
+{
+    float y = texture(texture_y, texture_coord).r;
+    vec2 uv;
+    if (nv12) {
+        uv = texture(texture_u, texture_coord).rg;
+    } else {
+        uv = vec2(texture(texture_u, texture_coord).r, texture(texture_v, texture_coord).r);
+    }
+
+    vec3 rgb = (yuv_to_rgb * vec4(y, uv, 1.0)).rgb;
+    FragColor = vec4(clamp(rgb, 0.0, 1.0), 1.0);
+}
+\0";
+
+// スペクトラムのバーかオシロスコープの線を描く
+const FS_VISUALIZER_SRC: &'static [u8] = b"
+#version 330 core
+out vec4 FragColor;
+
+in vec2 texture_coord;
+
+uniform sampler2D values;
+uniform bool scope;
+uniform float background;
+
+void main()
+{
+    // texture_coord has v = 0 at the top
+    vec2 uv = vec2(texture_coord.x, 1.0 - texture_coord.y);
+    vec4 color = vec4(0.0, 0.0, 0.0, background);
+    if (scope) {
+        float y = 0.5 + 0.5 * texture(values, vec2(uv.x, 0.5)).r;
+        // widen steep segments so the line stays connected
+        float width = max(fwidth(uv.y) * 1.5, abs(dFdx(y)));
+        if (abs(uv.y - y) < width) {
+            color = vec4(0.3, 0.9, 1.0, 1.0);
+        }
+    } else {
+        float x = uv.x * float(textureSize(values, 0).x);
+        float height = texelFetch(values, ivec2(int(x), 0), 0).r;
+        // leave a gap between bars
+        if (fract(x) < 0.8 && uv.y < height) {
+            color = vec4(mix(vec3(0.2, 0.8, 0.4), vec3(1.0, 0.3, 0.2), uv.y), 0.9);
+        }
+    }
+    FragColor = color;
+}
+\0";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 列優先の行列を vec4(y, u, v, 1) に掛ける
+    fn convert(color_space: ColorSpace, y: u8, u: u8, v: u8) -> [f32; 3] {
+        let m = color_space.yuv_to_rgb();
+        let input = [y as f32 / 255.0, u as f32 / 255.0, v as f32 / 255.0, 1.0];
+        let mut rgb = [0.0; 3];
+        for (row, out) in rgb.iter_mut().enumerate() {
+            *out = (0..4).map(|col| m[col * 4 + row] * input[col]).sum();
+        }
+        rgb
+    }
+
+    fn assert_rgb(actual: [f32; 3], expected: [f32; 3]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 0.005, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn converts_limited_range() {
+        for matrix in [ColorMatrix::Bt601, ColorMatrix::Bt709, ColorMatrix::Bt2020] {
+            let limited = ColorSpace { matrix, full_range: false };
+            assert_rgb(convert(limited, 16, 128, 128), [0.0, 0.0, 0.0]);
+            assert_rgb(convert(limited, 235, 128, 128), [1.0, 1.0, 1.0]);
+        }
+        // BT.709 の 100% の赤
+        let bt709 = ColorSpace { matrix: ColorMatrix::Bt709, full_range: false };
+        assert_rgb(convert(bt709, 63, 102, 240), [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn converts_full_range() {
+        let full = ColorSpace { matrix: ColorMatrix::Bt601, full_range: true };
+        assert_rgb(convert(full, 0, 128, 128), [0.0, 0.0, 0.0]);
+        assert_rgb(convert(full, 255, 128, 128), [1.0, 1.0, 1.0]);
+        // BT.601 (JPEG) の赤
+        assert_rgb(convert(full, 76, 85, 255), [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn keeps_range_of_negotiated_chroma() {
+        let format = PixelFormat::from_chroma("J420").unwrap();
+        assert_eq!(format.output_chroma("J420"), "J420");
+        assert_eq!(format.output_chroma("I420"), "I420");
+        assert!(ColorSpace::detect("J420", format.output_chroma("J420"), 720).full_range);
+        assert!(!ColorSpace::detect("J420", "I420", 720).full_range);
+    }
+
+    #[test]
+    fn parses_color_matrix() {
+        assert_eq!("601".parse(), Ok(ColorMatrix::Bt601));
+        assert_eq!("709".parse(), Ok(ColorMatrix::Bt709));
+        assert_eq!("2020".parse(), Ok(ColorMatrix::Bt2020));
+        assert_eq!("auto".parse::<ColorMatrix>(), Err(()));
+    }
+
+    #[test]
+    fn detects_color_matrix() {
+        assert_eq!(ColorMatrix::detect("I420", 480), ColorMatrix::Bt601);
+        assert_eq!(ColorMatrix::detect("I420", 1080), ColorMatrix::Bt709);
+        assert_eq!(ColorMatrix::detect("I420", 2160), ColorMatrix::Bt709);
+        assert_eq!(ColorMatrix::detect("I0AL", 2160), ColorMatrix::Bt2020);
+    }
+
+    #[test]
+    fn finds_error_line_in_driver_logs() {
+        assert_eq!(error_line("0(12) : error C0000: syntax error"), Some(12));
+        assert_eq!(error_line("0:7(5): error: `foo' undeclared"), Some(7));
+        assert_eq!(error_line("WARNING: 0:3: unused\nERROR: 0:9: 'x' : undeclared identifier"), Some(9));
+        assert_eq!(error_line("link failed"), None);
+    }
+
+    #[test]
+    fn reports_failing_source_line() {
+        let src = b"\n#version 330 core\nvoid main()\n{\n    bad;\n}\n\0";
+        assert_eq!(
+            failing_line(src, "0:5(5): error: syntax error"),
+            Some((5, "bad;".to_string()))
+        );
+        assert_eq!(failing_line(src, "0:50(1): error: ?"), None);
+    }
+}
@@ -0,0 +1,317 @@
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicI64, AtomicU32, AtomicUsize, Ordering};
+use std::time::Instant;
+
+// 2 のべき乗
+const RING_SIZE: usize = 16384;
+pub const FFT_SIZE: usize = 2048;
+// オシロスコープに表示するサンプル数
+pub const SCOPE_SIZE: usize = 1024;
+pub const BARS: usize = 64;
+// バーに割り当てる周波数の範囲 (Hz)
+const MIN_FREQ: f32 = 30.0;
+const MAX_FREQ: f32 = 16000.0;
+// 0 〜 1 に割り当てる範囲 (dB)
+const MIN_DB: f32 = -70.0;
+// 1 秒でバーが下がる量 (描き直す間隔によらない)
+const BAR_FALL: f32 = 1.2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visualization {
+    Spectrum,
+    Oscilloscope,
+}
+
+impl Visualization {
+    // なし → スペクトラム → オシロスコープ → なし
+    pub fn next(current: Option<Visualization>) -> Option<Visualization> {
+        match current {
+            None => Some(Visualization::Spectrum),
+            Some(Visualization::Spectrum) => Some(Visualization::Oscilloscope),
+            Some(Visualization::Oscilloscope) => None,
+        }
+    }
+}
+
+// play コールバックのスレッドが書き込み、描画のスレッドが読む
+// ロックしないので、読んでいる途中に書き換わったサンプルが混ざることがあるが表示だけなので気にしない
+pub struct SampleRing {
+    // f32 のビット列 (モノラルにまとめたもの)
+    samples: Box<[AtomicU32]>,
+    // これまでに書いたサンプル数
+    written: AtomicUsize,
+    // 最後に書いたサンプルの次の pts
+    end_pts: AtomicI64,
+    rate: AtomicU32,
+}
+
+impl SampleRing {
+    pub fn new() -> SampleRing {
+        SampleRing {
+            samples: (0..RING_SIZE).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicUsize::new(0),
+            end_pts: AtomicI64::new(0),
+            rate: AtomicU32::new(48000),
+        }
+    }
+
+    pub fn set_rate(&self, rate: u32) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    // samples はインターリーブ、pts は先頭のサンプルの時刻
+    pub fn push(&self, samples: &[f32], channels: usize, pts: i64) {
+        let mut written = self.written.load(Ordering::Relaxed);
+        for frame in samples.chunks_exact(channels) {
+            let mono = frame.iter().sum::<f32>() / channels as f32;
+            self.samples[written % RING_SIZE].store(mono.to_bits(), Ordering::Relaxed);
+            written += 1;
+        }
+        let rate = self.rate.load(Ordering::Relaxed) as i64;
+        let frames = (samples.len() / channels) as i64;
+        self.end_pts.store(pts + frames * 1_000_000 / rate, Ordering::Relaxed);
+        self.written.store(written, Ordering::Release);
+    }
+
+    // time に鳴っているサンプルで終わるように out を埋める
+    // play コールバックは鳴る時刻より先に呼ばれるので、その分さかのぼる
+    pub fn read(&self, out: &mut [f32], time: Option<i64>) {
+        let written = self.written.load(Ordering::Acquire);
+        let ahead = time.map_or(0, |time| {
+            let rate = self.rate.load(Ordering::Relaxed) as i64;
+            ((self.end_pts.load(Ordering::Relaxed) - time) * rate / 1_000_000).max(0) as usize
+        });
+        let ahead = ahead.min(RING_SIZE - out.len()).min(written);
+        let end = written - ahead;
+        let len = out.len();
+        for (i, sample) in out.iter_mut().enumerate() {
+            // まだ書かれていないところは無音
+            *sample = match (end + i).checked_sub(len) {
+                Some(index) => f32::from_bits(self.samples[index % RING_SIZE].load(Ordering::Relaxed)),
+                None => 0.0,
+            };
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+// 基数 2 の FFT (data.len() は 2 のべき乗)
+fn fft(data: &mut [Complex]) {
+    let n = data.len();
+    // ビット反転の並べ替え
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let a = data[start + k];
+                let b = data[start + k + len / 2];
+                let t = Complex {
+                    re: b.re * cos - b.im * sin,
+                    im: b.re * sin + b.im * cos,
+                };
+                data[start + k] = Complex { re: a.re + t.re, im: a.im + t.im };
+                data[start + k + len / 2] = Complex { re: a.re - t.re, im: a.im - t.im };
+            }
+        }
+        len <<= 1;
+    }
+}
+
+// リングバッファのサンプルから表示する値を作る
+pub struct Analyzer {
+    samples: Vec<f32>,
+    window: Vec<f32>,
+    spectrum: Vec<Complex>,
+    bars: Vec<f32>,
+    scope: Vec<f32>,
+    // 前にバーを更新した時刻
+    last_update: Option<Instant>,
+}
+
+impl Analyzer {
+    pub fn new() -> Analyzer {
+        Analyzer {
+            samples: vec![0.0; FFT_SIZE],
+            // ハン窓
+            window: (0..FFT_SIZE)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_SIZE as f32).cos())
+                .collect(),
+            spectrum: vec![Complex::default(); FFT_SIZE],
+            bars: vec![0.0; BARS],
+            scope: vec![0.0; SCOPE_SIZE],
+            last_update: None,
+        }
+    }
+
+    // 対数で並べた BARS 本のバーの高さ (0 〜 1)、描画するたびに呼ぶ
+    pub fn spectrum(&mut self, ring: &SampleRing, time: Option<i64>) -> &[f32] {
+        ring.read(&mut self.samples, time);
+        let rate = ring.rate.load(Ordering::Relaxed) as f32;
+        let now = Instant::now();
+        let elapsed = self.last_update.map_or(0.0, |last| now.duration_since(last).as_secs_f32());
+        self.last_update = Some(now);
+        self.update_bars(rate, elapsed);
+        &self.bars
+    }
+
+    // elapsed は前に更新してからの秒数
+    fn update_bars(&mut self, rate: f32, elapsed: f32) {
+        for ((bin, sample), window) in self.spectrum.iter_mut().zip(&self.samples).zip(&self.window) {
+            *bin = Complex { re: sample * window, im: 0.0 };
+        }
+        fft(&mut self.spectrum);
+
+        let max_freq = MAX_FREQ.min(rate / 2.0);
+        let bin_width = rate / FFT_SIZE as f32;
+        for (i, bar) in self.bars.iter_mut().enumerate() {
+            let low = MIN_FREQ * (max_freq / MIN_FREQ).powf(i as f32 / BARS as f32);
+            let high = MIN_FREQ * (max_freq / MIN_FREQ).powf((i + 1) as f32 / BARS as f32);
+            // 低い方は 1 本のバーが 1 ビンより狭いので、ビンがなければすぐ上のビンを見る
+            let first = ((low / bin_width).ceil() as usize).min(FFT_SIZE / 2 - 1);
+            let last = ((high / bin_width).ceil() as usize).clamp(first + 1, FFT_SIZE / 2);
+            let peak = self.spectrum[first..last]
+                .iter()
+                .map(|bin| (bin.re * bin.re + bin.im * bin.im).sqrt())
+                .fold(0.0, f32::max);
+            // ハン窓で半分になるので、振幅 1 の正弦波が 0dB になるように合わせる
+            let db = 20.0 * (peak * 4.0 / FFT_SIZE as f32).max(1e-9).log10();
+            let height = ((db - MIN_DB) / -MIN_DB).clamp(0.0, 1.0);
+            // 上がるときはすぐ、下がるときはゆっくり
+            *bar = height.max(*bar - BAR_FALL * elapsed);
+        }
+    }
+
+    // 波形 (-1 〜 1)、ゼロを上向きに横切るところから表示して揺れないようにする
+    pub fn scope(&mut self, ring: &SampleRing, time: Option<i64>) -> &[f32] {
+        ring.read(&mut self.samples, time);
+        let search = FFT_SIZE - SCOPE_SIZE;
+        let start = (1..search)
+            .find(|&i| self.samples[i - 1] < 0.0 && self.samples[i] >= 0.0)
+            .unwrap_or(search);
+        self.scope.copy_from_slice(&self.samples[start..start + SCOPE_SIZE]);
+        &self.scope
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, rate: f32, len: usize) -> Vec<f32> {
+        (0..len).map(|i| (2.0 * PI * freq * i as f32 / rate).sin()).collect()
+    }
+
+    #[test]
+    fn fft_finds_tone_bin() {
+        let mut data: Vec<Complex> = sine(64.0, 1024.0, 1024)
+            .into_iter()
+            .map(|re| Complex { re, im: 0.0 })
+            .collect();
+        fft(&mut data);
+        let magnitudes: Vec<f32> = data[..512].iter().map(|bin| bin.re.hypot(bin.im)).collect();
+        let peak = (0..512).max_by(|&a, &b| magnitudes[a].total_cmp(&magnitudes[b])).unwrap();
+        assert_eq!(peak, 64);
+        assert!((magnitudes[64] - 512.0).abs() < 0.1);
+        assert!(magnitudes[100] < 1e-3);
+    }
+
+    #[test]
+    fn ring_reads_latest_samples() {
+        let ring = SampleRing::new();
+        // ステレオを平均してモノラルにする
+        let samples: Vec<f32> = (0..20).flat_map(|i| [i as f32, i as f32 + 1.0]).collect();
+        ring.push(&samples, 2, 0);
+        let mut out = [0.0; 4];
+        ring.read(&mut out, None);
+        assert_eq!(out, [16.5, 17.5, 18.5, 19.5]);
+    }
+
+    #[test]
+    fn ring_goes_back_to_audible_time() {
+        let ring = SampleRing::new();
+        ring.set_rate(1000);
+        let samples: Vec<f32> = (0..100).map(|i| i as f32).collect();
+        // 0 〜 100ms のサンプルで、いま鳴っているのは 50ms
+        ring.push(&samples, 1, 0);
+        let mut out = [0.0; 3];
+        ring.read(&mut out, Some(50_000));
+        assert_eq!(out, [47.0, 48.0, 49.0]);
+        // 足りない分は無音
+        let mut out = [1.0; 3];
+        ring.read(&mut out, Some(1_000));
+        assert_eq!(out, [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn ring_wraps_around() {
+        let ring = SampleRing::new();
+        let samples: Vec<f32> = (0..RING_SIZE + 10).map(|i| i as f32).collect();
+        ring.push(&samples, 1, 0);
+        let mut out = [0.0; 2];
+        ring.read(&mut out, None);
+        assert_eq!(out, [(RING_SIZE + 8) as f32, (RING_SIZE + 9) as f32]);
+    }
+
+    #[test]
+    fn spectrum_peaks_at_tone() {
+        let mut analyzer = Analyzer::new();
+        analyzer.samples = sine(1000.0, 48000.0, FFT_SIZE);
+        analyzer.update_bars(48000.0, 0.0);
+        let peak = (0..BARS).max_by(|&a, &b| analyzer.bars[a].total_cmp(&analyzer.bars[b])).unwrap();
+        // 1kHz が入るバー
+        let expected = (BARS as f32 * (1000.0f32 / MIN_FREQ).ln() / (MAX_FREQ / MIN_FREQ).ln()) as usize;
+        assert_eq!(peak, expected);
+        assert!(analyzer.bars[peak] > 0.95);
+        assert!(analyzer.bars[BARS - 1] < 0.2);
+    }
+
+    #[test]
+    fn bars_fall_by_elapsed_time() {
+        let mut analyzer = Analyzer::new();
+        analyzer.bars = vec![1.0; BARS];
+        // 無音でも一度に全部は下がらない
+        analyzer.update_bars(48000.0, 0.25);
+        assert!(analyzer.bars.iter().all(|&bar| (bar - (1.0 - BAR_FALL * 0.25)).abs() < 1e-6));
+        // 描き直す回数が違っても同じ時間で同じだけ下がる
+        let mut fast = Analyzer::new();
+        fast.bars = vec![1.0; BARS];
+        for _ in 0..5 {
+            fast.update_bars(48000.0, 0.05);
+        }
+        assert!((fast.bars[0] - analyzer.bars[0]).abs() < 1e-5);
+        analyzer.update_bars(48000.0, 1.0);
+        assert!(analyzer.bars.iter().all(|&bar| bar == 0.0));
+    }
+
+    #[test]
+    fn scope_starts_at_rising_zero_crossing() {
+        let ring = SampleRing::new();
+        ring.set_rate(48000);
+        let samples: Vec<f32> = sine(440.0, 48000.0, FFT_SIZE + 37).into_iter().map(|s| -s).collect();
+        ring.push(&samples, 1, 0);
+        let mut analyzer = Analyzer::new();
+        let scope = analyzer.scope(&ring, None);
+        assert!(scope[0] >= 0.0 && scope[0] < 0.1);
+        assert!(scope[1] > scope[0]);
+    }
+}